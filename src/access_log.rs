@@ -0,0 +1,172 @@
+// Per-request spans and access logs
+// Every request gets a `request` span with its id, method, matched route, status, latency and response size,
+// so anything a handler logs can be tied back to the request it belongs to
+// When the request completes, we emit one access log event, in one of these formats:
+//   `default`   a structured event, with the request details as fields of its span
+//   `common`    Common Log Format:   127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 13
+//   `combined`  Combined Log Format: Common Log Format, followed by the quoted referer and user agent
+//   `off`       no access log events (the spans are still created)
+// The client address is the one the rate limiter uses: the peer, or the `X-Forwarded-For` address
+// our trusted proxies got the request from
+
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use std::{str::FromStr, sync::Arc, time::Instant};
+use time::{macros::format_description, OffsetDateTime};
+use tracing::{field::Empty, Instrument};
+
+use crate::{
+    rate_limit::{self, TrustedProxy},
+    request_id::RequestId,
+    telemetry,
+};
+
+/// Which format access log events are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Default,
+    Common,
+    Combined,
+    Off,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(AccessLogFormat::Default),
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "off" => Ok(AccessLogFormat::Off),
+            _ => Err("expected one of default, common, combined or off".into()),
+        }
+    }
+}
+
+/// Settings for the access log middleware.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    pub format: AccessLogFormat,
+    /// Paths that get neither a span nor an access log event, like health checks.
+    pub exclude: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` we believe, see `rate_limit.trusted_proxies`.
+    pub trusted_proxies: Arc<[TrustedProxy]>,
+}
+
+/// Middleware that opens a span per request, and logs the request once it completes.
+pub async fn middleware(
+    State(access_log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if access_log
+        .exclude
+        .iter()
+        .any(|path| path == request.uri().path())
+    {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let received = OffsetDateTime::now_utc();
+    let method = request.method().clone();
+    let uri = request.uri().clone();
+    let version = request.version();
+    // The route template, like `/users/{id}`, keeps the number of distinct values small
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned());
+    // The log formats only need the client and a few headers, so we copy those instead of the whole map
+    let client = rate_limit::client_ip(&request, &access_log.trusted_proxies);
+    let referer = header_str(request.headers(), header::REFERER).to_owned();
+    let user_agent = header_str(request.headers(), header::USER_AGENT).to_owned();
+
+    let request_id = request.extensions().get::<RequestId>().cloned();
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = request_id.as_ref().map(RequestId::as_str),
+        method = %method,
+        route = route.as_deref(),
+        path = uri.path(),
+        status = Empty,
+        latency_ms = Empty,
+        size = Empty,
+        // These are picked up by OpenTelemetry, to name the exported span and mark it as a server span
+        otel.name = format!("{} {}", method, route.as_deref().unwrap_or(uri.path())),
+        otel.kind = "server",
+    );
+    // Continue the caller's trace, if they sent one
+    telemetry::set_parent(&span, request.headers());
+
+    let response = next.run(request).instrument(span.clone()).await;
+
+    let status = response.status().as_u16();
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    let size = response_size(&response);
+    span.record("status", status);
+    span.record("latency_ms", latency_ms);
+    if let Some(size) = size {
+        span.record("size", size);
+    }
+
+    span.in_scope(|| match access_log.format {
+        // The request details are already fields of the span, which every log format includes
+        AccessLogFormat::Default => tracing::info!("Request completed"),
+        AccessLogFormat::Common | AccessLogFormat::Combined => {
+            let request_line = format!("{} {} {:?}", method, uri, version);
+            let mut line = format!(
+                "{} - - [{}] \"{}\" {} {}",
+                client,
+                received
+                    .format(format_description!(
+                        "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+                    ))
+                    .unwrap_or_default(),
+                escape(&request_line),
+                status,
+                size.map_or("-".to_owned(), |size| size.to_string()),
+            );
+            if access_log.format == AccessLogFormat::Combined {
+                line.push_str(&format!(
+                    " \"{}\" \"{}\"",
+                    escape(&referer),
+                    escape(&user_agent),
+                ));
+            }
+            tracing::info!("{}", line);
+        }
+        AccessLogFormat::Off => {}
+    });
+
+    response
+}
+
+// Prefers the `Content-Length` header, and falls back to the body's size when it is known up front
+fn response_size(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .or_else(|| response.body().size_hint().exact())
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> &str {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+}
+
+// Quotes and backslashes would break the quoted fields of the log line
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,127 @@
+// Admin routes, for operating the server while it runs
+// Every route here requires `Authorization: Bearer <admin.token>`, and none are mounted without a token
+//
+// `GET /admin/log-filter` returns the active filter directives
+// `PUT /admin/log-filter` replaces them, for example with
+//     {"directives": "info,rust_starter=debug", "revert_after_secs": 900}
+// which turns on debug logs for our crate for 15 minutes
+
+use axum::{
+    extract::{rejection::JsonRejection, Request, State},
+    http::header,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    config::Secret,
+    error::{AppError, Problem},
+    logging::LogFilter,
+};
+
+/// Routes for the admin API, protected by `token`.
+pub fn routes(token: Secret<String>, log_filter: LogFilter) -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(get_log_filter, put_log_filter))
+        .with_state(log_filter)
+        .route_layer(middleware::from_fn_with_state(token, require_token))
+}
+
+// Rejects requests that don't carry the admin token
+async fn require_token(
+    State(token): State<Secret<String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if constant_time_eq(provided.as_bytes(), token.expose().as_bytes()) => {
+            next.run(request).await
+        }
+        _ => (
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            AppError::Unauthorized("missing or invalid admin token".into()),
+        )
+            .into_response(),
+    }
+}
+
+// Compares every byte, so the time taken doesn't reveal how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The active log filter.
+#[derive(Serialize, ToSchema)]
+struct LogFilterState {
+    /// `RUST_LOG`-style filter directives, like `info,rust_starter=debug`.
+    directives: String,
+    /// When set, the previous directives are restored after this many seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revert_after_secs: Option<u64>,
+}
+
+/// A new log filter.
+#[derive(Deserialize, ToSchema)]
+struct SetLogFilter {
+    /// `RUST_LOG`-style filter directives, like `info,rust_starter=debug`.
+    directives: String,
+    /// When given, the previous directives are restored after this many seconds.
+    revert_after_secs: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-filter",
+    tag = "admin",
+    summary = "Get the log filter",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "The active log filter", body = LogFilterState),
+        (status = 401, description = "Missing or invalid admin token", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn get_log_filter(State(log_filter): State<LogFilter>) -> Json<LogFilterState> {
+    Json(LogFilterState {
+        directives: log_filter.directives(),
+        revert_after_secs: None,
+    })
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/log-filter",
+    tag = "admin",
+    summary = "Replace the log filter",
+    security(("admin_token" = [])),
+    request_body = SetLogFilter,
+    responses(
+        (status = 200, description = "The new log filter", body = LogFilterState),
+        (status = 400, description = "Invalid filter directives", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing or invalid admin token", body = Problem, content_type = "application/problem+json"),
+    ),
+)]
+async fn put_log_filter(
+    State(log_filter): State<LogFilter>,
+    body: Result<Json<SetLogFilter>, JsonRejection>,
+) -> Result<Json<LogFilterState>, AppError> {
+    let Json(body) = body?;
+    let revert_after = body.revert_after_secs.map(Duration::from_secs);
+    log_filter
+        .set(&body.directives, revert_after)
+        .map_err(AppError::BadRequest)?;
+    Ok(Json(LogFilterState {
+        directives: log_filter.directives(),
+        revert_after_secs: body.revert_after_secs,
+    }))
+}
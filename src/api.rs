@@ -0,0 +1,150 @@
+// Our versioned API
+// Each version is its own router, nested under `/v1`, `/v2`, ... by `versioning::nest`
+// A handler whose contract changed gets a new version in that version's module, like `complex` below
+// A handler that didn't change goes in `shared`, which every version includes, so there is only one copy of it
+//
+// v1 is deprecated in favour of v2; its deprecation and sunset dates come from `api.v1_deprecated` and `api.v1_sunset` in our config
+// Routes added since v2, like `/v2/me`, only exist in v2
+// `/complex` is from before we versioned the API, so it stays as an alias of `/v1/complex` for the clients that still call it
+
+use axum::Json;
+use serde::Serialize;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::{
+    auth::Claims,
+    config::Config,
+    error::{AppError, Problem},
+    state::AppState,
+    versioning::{self, ApiVersion},
+};
+
+/// Every version of our API, each nested under its own prefix, and the unversioned aliases of v1.
+pub fn routes(config: &Config) -> Result<OpenApiRouter<AppState>, String> {
+    let v1 = ApiVersion {
+        name: "v1",
+        deprecated: Some(config.api_v1_deprecated),
+        sunset: config.api_v1_sunset,
+        successor: Some("v2"),
+    };
+    let app = versioning::nest(OpenApiRouter::new(), v1.clone(), v1::routes())?;
+    let app = versioning::alias(app, v1, OpenApiRouter::new().routes(routes!(v1::complex)))?;
+    versioning::nest(app, ApiVersion::current("v2"), v2::routes())
+}
+
+// Handlers that are the same in every version
+fn shared() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(routes!(crate::root))
+}
+
+mod v1 {
+    use super::*;
+
+    pub(super) fn routes() -> OpenApiRouter<AppState> {
+        shared().routes(routes!(complex))
+    }
+
+    // This is the body of the complex route
+    // `Serialize` turns it into JSON, and `ToSchema` describes that JSON in our OpenAPI document
+
+    /// A message for the client.
+    #[derive(Serialize, ToSchema)]
+    pub struct Message {
+        /// The message itself.
+        #[schema(example = "Hello, World!")]
+        pub message: String,
+    }
+
+    // This is our route handler, for the route complex
+    // Make sure the function is async
+    // We specify our return type, this time using a `Result`
+    // The `Ok` side is anything that implements `IntoResponse`, and the `Err` side is our `AppError`
+    // That lets us use `?` on anything that can fail, and every error becomes a problem+json response
+
+    #[utoipa::path(
+        get,
+        path = "/complex",
+        tag = "examples",
+        summary = "Say hello, in JSON",
+        responses(
+            (status = 200, description = "A greeting", body = Message),
+            (status = 500, description = "Something went wrong", body = Problem, content_type = "application/problem+json"),
+        ),
+    )]
+    pub(super) async fn complex() -> Result<Json<Message>, AppError> {
+        // For this route, we are going to return a Json response
+        // The response body is a `Json` instance, wrapping our `Message` struct
+        // Without a status code, the response is a 200 OK
+        Ok(Json(Message {
+            message: "Hello, World!".into(),
+        }))
+    }
+}
+
+mod v2 {
+    use super::*;
+
+    pub(super) fn routes() -> OpenApiRouter<AppState> {
+        shared().routes(routes!(complex)).routes(routes!(me))
+    }
+
+    // In v2, the complex route also says which language its greeting is in
+    // Renaming `message` to `text` would break v1 clients, which is why this is a new version
+
+    /// A greeting, and the language it is in.
+    #[derive(Serialize, ToSchema)]
+    pub struct Greeting {
+        /// The greeting itself.
+        #[schema(example = "Hello, World!")]
+        pub text: String,
+        /// The language of `text`, as a BCP 47 tag.
+        #[schema(example = "en")]
+        pub language: String,
+    }
+
+    #[utoipa::path(
+        get,
+        path = "/complex",
+        tag = "examples",
+        summary = "Say hello, in JSON",
+        responses(
+            (status = 200, description = "A greeting", body = Greeting),
+            (status = 500, description = "Something went wrong", body = Problem, content_type = "application/problem+json"),
+        ),
+    )]
+    pub(super) async fn complex() -> Result<Json<Greeting>, AppError> {
+        Ok(Json(Greeting {
+            text: "Hello, World!".into(),
+            language: "en".into(),
+        }))
+    }
+
+    /// The user a bearer token was issued to.
+    #[derive(Serialize, ToSchema)]
+    pub struct User {
+        /// The subject of the token.
+        #[schema(example = "user-123")]
+        pub id: String,
+    }
+
+    // Taking `Claims` is all it takes to protect a route: without a valid bearer token, the handler never runs
+
+    #[utoipa::path(
+        get,
+        path = "/me",
+        tag = "examples",
+        summary = "Who the bearer token is for",
+        security(("jwt" = [])),
+        responses(
+            (status = 200, description = "The user the token was issued to", body = User),
+            (status = 401, description = "Missing or invalid bearer token", body = Problem, content_type = "application/problem+json"),
+        ),
+    )]
+    pub(super) async fn me(claims: Claims) -> Json<User> {
+        Json(User { id: claims.sub })
+    }
+}
+
+pub use v1::Message;
+pub use v2::{Greeting, User};
@@ -0,0 +1,417 @@
+// Authentication with bearer JWTs
+// A handler that takes `Claims` only runs for requests with a valid `Authorization: Bearer <token>`; anyone else gets a 401
+// That makes protecting a route declarative: add a `Claims` argument, or `Option<Claims>` for routes that work either way
+//
+// Tokens are signed with either:
+//   HS256           a secret we share with the issuer, `auth.secret`
+//   RS256 or EdDSA  the issuer's private key; it publishes the public keys as a JWKS at `auth.jwks_url`,
+//                   and the token's `kid` header says which key signed it
+// The JWKS is fetched on first use, parsed once, and kept by the authenticator for `auth.jwks_ttl`
+// A token signed by a key we don't know yet fetches it again (at most every 30s), so rotated keys are picked up right away
+// Only one request fetches it at a time; any others that need it wait for that fetch and share its result
+// When a fetch fails, we keep using the set we have, even once it has expired, and don't try again for 5s,
+// so an issuer that is down doesn't get a fetch from every request
+// Our middleware checks any token up front, and leaves the result in the request for the `Claims` extractor
+//
+// Tokens must not have expired (give or take `auth.leeway`, for clocks that drift apart),
+// and must come from `auth.issuer` for `auth.audience`, when those are set
+
+use axum::{
+    extract::{FromRef, FromRequestParts, OptionalFromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{
+    errors::ErrorKind,
+    jwk::{JwkSet, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use reqwest_middleware::ClientWithMiddleware;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{config::Config, error::AppError, rate_limit::UserId};
+
+// Tokens from unknown keys could otherwise make us fetch the JWKS on every request
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+// How long we wait after a failed fetch before trying again
+const FAILED_FETCH_BACKOFF: Duration = Duration::from_secs(5);
+
+/// The claims of a valid token.
+///
+/// As an extractor, it rejects requests without a valid bearer token with a 401.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Who the token was issued to, usually a user id.
+    pub sub: String,
+    /// Who issued the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    /// When the token expires, in seconds since the Unix epoch.
+    pub exp: u64,
+    /// Every other claim, like `aud`, `scope`, or the issuer's own.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Why a request wasn't authenticated.
+#[derive(Debug, Clone)]
+pub enum AuthRejection {
+    /// The request has no bearer token.
+    Missing,
+    /// The token isn't valid, with the reason.
+    Invalid(String),
+    /// We couldn't check the token, because authentication isn't configured or the JWKS couldn't be fetched.
+    Unavailable(String),
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        // RFC 6750 tells clients what went wrong in `WWW-Authenticate`
+        match self {
+            AuthRejection::Missing => (
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                AppError::Unauthorized("missing bearer token".into()),
+            )
+                .into_response(),
+            AuthRejection::Invalid(detail) => (
+                [(header::WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)],
+                AppError::Unauthorized(detail),
+            )
+                .into_response(),
+            AuthRejection::Unavailable(detail) => {
+                AppError::Status(StatusCode::SERVICE_UNAVAILABLE, detail).into_response()
+            }
+        }
+    }
+}
+
+/// Checks tokens against the keys and rules from our config.
+///
+/// Cloning is cheap; every clone shares the same state.
+#[derive(Clone)]
+pub struct Authenticator {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    secret: Option<DecodingKey>,
+    jwks_url: Option<String>,
+    jwks_ttl: Duration,
+    validation: Validation,
+    http: ClientWithMiddleware,
+    jwks: RwLock<JwksState>,
+    // Held while the JWKS is being fetched
+    fetching: tokio::sync::Mutex<()>,
+    // When a token signed by an unknown key last made us fetch the JWKS again
+    last_refetch: Mutex<Option<Instant>>,
+}
+
+// The JWKS we have, and how the fetches for it went
+#[derive(Default)]
+struct JwksState {
+    // The last set we fetched, and when
+    jwks: Option<(Arc<JwkSet>, Instant)>,
+    // When the last fetch failed, unless one has succeeded since
+    failed: Option<Instant>,
+    // Bumped by every fetch, so a request that waited for one can tell it has finished
+    fetches: u64,
+}
+
+impl JwksState {
+    // The set to use when we can't fetch a better one: the last one, even if it has expired
+    fn last(&self) -> Result<(Arc<JwkSet>, bool), AuthRejection> {
+        match &self.jwks {
+            Some((jwks, _)) => Ok((jwks.clone(), true)),
+            None => Err(AuthRejection::Unavailable(
+                "the keys to check the token with are unavailable".into(),
+            )),
+        }
+    }
+}
+
+impl Authenticator {
+    /// An authenticator that fetches the JWKS with `http`.
+    pub fn new(config: &Config, http: ClientWithMiddleware) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.algorithms = config.auth_algorithms.clone();
+        validation.leeway = config.auth_leeway.as_secs();
+        validation.validate_nbf = true;
+        if let Some(issuer) = &config.auth_issuer {
+            validation.set_issuer(&[issuer]);
+            validation.required_spec_claims.insert("iss".into());
+        }
+        // Without an audience of our own, tokens meant for anyone else would be rejected
+        if config.auth_audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&config.auth_audience);
+            validation.required_spec_claims.insert("aud".into());
+        }
+
+        Self {
+            inner: Arc::new(Inner {
+                secret: config
+                    .auth_secret
+                    .as_ref()
+                    .map(|secret| DecodingKey::from_secret(secret.expose().as_bytes())),
+                jwks_url: config.auth_jwks_url.clone(),
+                jwks_ttl: config.auth_jwks_ttl,
+                validation,
+                http,
+                jwks: RwLock::default(),
+                fetching: tokio::sync::Mutex::new(()),
+                last_refetch: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Whether there are any keys to check tokens with.
+    pub fn is_configured(&self) -> bool {
+        self.inner.secret.is_some() || self.inner.jwks_url.is_some()
+    }
+
+    /// The claims of `token`, if it is valid.
+    pub async fn authenticate(&self, token: &str) -> Result<Claims, AuthRejection> {
+        if !self.is_configured() {
+            return Err(AuthRejection::Unavailable(
+                "authentication isn't configured".into(),
+            ));
+        }
+
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| AuthRejection::Invalid("the token is malformed".into()))?;
+        if !self.inner.validation.algorithms.contains(&header.alg) {
+            return Err(AuthRejection::Invalid(format!(
+                "tokens signed with {:?} aren't accepted",
+                header.alg
+            )));
+        }
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                self.inner.secret.clone().ok_or_else(|| {
+                    AuthRejection::Invalid(format!(
+                        "tokens signed with {:?} aren't accepted",
+                        header.alg
+                    ))
+                })?
+            }
+            alg => {
+                let kid = header
+                    .kid
+                    .ok_or_else(|| AuthRejection::Invalid("the token has no key id".into()))?;
+                self.public_key(&kid, alg).await?
+            }
+        };
+
+        // Every algorithm a token is checked against must fit its key, so it is only checked against its own
+        let mut validation = self.inner.validation.clone();
+        validation.algorithms = vec![header.alg];
+        jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|error| AuthRejection::Invalid(reason(error.kind())))
+    }
+
+    // The key `kid` from the JWKS, fetching it again if the key is new to us
+    async fn public_key(&self, kid: &str, alg: Algorithm) -> Result<DecodingKey, AuthRejection> {
+        let (mut jwks, fetched) = self.jwks(None).await?;
+        // A JWKS we fetched just now is as new as it gets
+        if !fetched && jwks.find(kid).is_none() && self.may_refetch() {
+            (jwks, _) = self.jwks(Some(&jwks)).await?;
+        }
+        let jwk = jwks.find(kid).ok_or_else(|| {
+            AuthRejection::Invalid("the token was signed with an unknown key".into())
+        })?;
+
+        // A key is only used for what the JWKS says it is for
+        let wrong_use = jwk
+            .common
+            .public_key_use
+            .as_ref()
+            .is_some_and(|key_use| *key_use != PublicKeyUse::Signature);
+        let wrong_algorithm = jwk
+            .common
+            .key_algorithm
+            .is_some_and(|key_algorithm| Algorithm::try_from(key_algorithm).ok() != Some(alg));
+        if wrong_use || wrong_algorithm {
+            return Err(AuthRejection::Invalid(
+                "the token's key can't be used with its algorithm".into(),
+            ));
+        }
+        DecodingKey::from_jwk(jwk)
+            .map_err(|_| AuthRejection::Invalid("the token's key isn't supported".into()))
+    }
+
+    // Claims the next refetch for a token signed by an unknown key, unless one was made less than 30s ago
+    fn may_refetch(&self) -> bool {
+        let mut last_refetch = self.inner.last_refetch.lock().unwrap();
+        if last_refetch.is_some_and(|last_refetch| last_refetch.elapsed() < MIN_REFETCH_INTERVAL) {
+            return false;
+        }
+        *last_refetch = Some(Instant::now());
+        true
+    }
+
+    // The JWKS, and whether it is as new as we can get it right now:
+    // we fetched it for this call (we didn't have it yet, or it's older than `auth.jwks_ttl`), or fetching failed
+    // With `outdated`, the set a caller already has is missing a key, so it's fetched again unless someone else just did
+    async fn jwks(
+        &self,
+        outdated: Option<&Arc<JwkSet>>,
+    ) -> Result<(Arc<JwkSet>, bool), AuthRejection> {
+        let Some(url) = &self.inner.jwks_url else {
+            return Ok((Arc::default(), false));
+        };
+        let fetches = {
+            let state = self.inner.jwks.read().unwrap();
+            if let Some(jwks) = self.cached(&state, outdated) {
+                return Ok((jwks, false));
+            }
+            state.fetches
+        };
+
+        let _fetching = self.inner.fetching.lock().await;
+        {
+            let state = self.inner.jwks.read().unwrap();
+            // A fetch finished while we waited for it, so its result is ours too
+            if state.fetches != fetches {
+                return state.last();
+            }
+            if state
+                .failed
+                .is_some_and(|failed| failed.elapsed() < FAILED_FETCH_BACKOFF)
+            {
+                return state.last();
+            }
+        }
+
+        let result = self.fetch(url).await;
+        let mut state = self.inner.jwks.write().unwrap();
+        state.fetches += 1;
+        match result {
+            Ok(jwks) => {
+                let jwks = Arc::new(jwks);
+                state.jwks = Some((jwks.clone(), Instant::now()));
+                state.failed = None;
+                Ok((jwks, true))
+            }
+            Err(error) => {
+                tracing::warn!(%error, url, "Failed to fetch the JWKS");
+                state.failed = Some(Instant::now());
+                state.last()
+            }
+        }
+    }
+
+    // The JWKS we have, unless it has expired or is the `outdated` one
+    fn cached(&self, state: &JwksState, outdated: Option<&Arc<JwkSet>>) -> Option<Arc<JwkSet>> {
+        let (jwks, fetched) = state.jwks.as_ref()?;
+        let fresh = fetched.elapsed() < self.inner.jwks_ttl
+            && outdated.is_none_or(|outdated| !Arc::ptr_eq(outdated, jwks));
+        fresh.then(|| jwks.clone())
+    }
+
+    async fn fetch(&self, url: &str) -> Result<JwkSet, String> {
+        let response = self
+            .inner
+            .http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status().map_err(Into::into))
+            .map_err(|error| error.to_string())?;
+        let value: serde_json::Value = response.json().await.map_err(|error| error.to_string())?;
+        serde_json::from_value(value).map_err(|error| format!("the JWKS is invalid: {error}"))
+    }
+}
+
+// What we tell the client about an invalid token
+fn reason(kind: &ErrorKind) -> String {
+    match kind {
+        ErrorKind::ExpiredSignature => "the token has expired".into(),
+        ErrorKind::ImmatureSignature => "the token isn't valid yet".into(),
+        ErrorKind::InvalidIssuer => "the token has the wrong issuer".into(),
+        ErrorKind::InvalidAudience => "the token has the wrong audience".into(),
+        ErrorKind::InvalidSignature => "the token's signature is invalid".into(),
+        ErrorKind::MissingRequiredClaim(claim) => format!("the token has no {claim} claim"),
+        _ => "the token is invalid".into(),
+    }
+}
+
+// The outcome of checking a request's token, so it is only checked once
+#[derive(Clone)]
+struct Authenticated(Result<Claims, AuthRejection>);
+
+// The token from `Authorization: Bearer <token>`
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+impl<S> FromRequestParts<S> for Claims
+where
+    Authenticator: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Already checked by our middleware, or an earlier extractor
+        if let Some(Authenticated(result)) = parts.extensions.get::<Authenticated>() {
+            return result.clone();
+        }
+        let token = bearer_token(&parts.headers).ok_or(AuthRejection::Missing)?;
+        let result = Authenticator::from_ref(state).authenticate(token).await;
+        parts.extensions.insert(Authenticated(result.clone()));
+        result
+    }
+}
+
+// Requests without a token are let through with `None`, but an invalid token is still rejected
+impl<S> OptionalFromRequestParts<S> for Claims
+where
+    Authenticator: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        if bearer_token(&parts.headers).is_none() {
+            return Ok(None);
+        }
+        <Claims as FromRequestParts<S>>::from_request_parts(parts, state)
+            .await
+            .map(Some)
+    }
+}
+
+/// Middleware that checks any bearer token up front, without rejecting anything.
+/// Requests with a valid token get their `Claims`, and the token's subject as their `UserId`,
+/// so rate limits can tell users apart before a handler runs.
+/// The `Claims` extractor reuses the result, rather than checking the token again.
+pub async fn middleware(
+    State(authenticator): State<Authenticator>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(token) = bearer_token(request.headers()) {
+        let result = authenticator.authenticate(token).await;
+        if let Ok(claims) = &result {
+            request.extensions_mut().insert(UserId(claims.sub.clone()));
+            request.extensions_mut().insert(claims.clone());
+        }
+        request.extensions_mut().insert(Authenticated(result));
+    }
+    next.run(request).await
+}
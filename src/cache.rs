@@ -0,0 +1,107 @@
+// A small in-memory cache, shared by every request
+// Entries expire after a fixed time to live, and the cache holds at most `max_entries` of them
+// When it is full, expired entries are dropped first, then the entries closest to expiring
+// This is per process: with several replicas, each one has its own cache
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// An in-memory cache with a time to live for every entry.
+///
+/// Cloning is cheap; every clone shares the same entries.
+#[derive(Clone)]
+pub struct Cache<K, V> {
+    inner: Arc<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+    entries: Mutex<HashMap<K, Entry<V>>>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+struct Entry<V> {
+    value: V,
+    expires: Instant,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                entries: Mutex::new(HashMap::new()),
+                ttl,
+                max_entries,
+            }),
+        }
+    }
+
+    /// The cached value for `key`, unless it is missing or has expired.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.inner.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches `value` for the cache's time to live.
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_with_ttl(key, value, self.inner.ttl);
+    }
+
+    /// Caches `value` for `ttl`, for values that know how long they stay fresh.
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        if self.inner.max_entries == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.inner.entries.lock().unwrap();
+        if entries.len() >= self.inner.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= self.inner.max_entries && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(
+            key,
+            Entry {
+                value,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    /// The cached value for `key`, or the result of `load`, which is cached when it succeeds.
+    pub async fn get_or_try_insert<E, F>(&self, key: K, load: F) -> Result<V, E>
+    where
+        F: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key) {
+            return Ok(value);
+        }
+        let value = load.await?;
+        self.insert(key, value.clone());
+        Ok(value)
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.inner.entries.lock().unwrap().remove(key);
+    }
+}
@@ -0,0 +1,68 @@
+// Response compression and request decompression, both from `tower-http`
+// Responses are compressed with zstd, brotli or gzip, whichever the client prefers in its `Accept-Encoding` header
+// Only responses that are worth it are compressed: those of at least `compression.min_size` bytes,
+// with a content type from `compression.content_types`
+// Server-sent events and gRPC are never compressed, even if their content type is allowed:
+// both stream messages that the client must get as soon as they're sent, not once a compressor's buffer fills up
+//
+// Request bodies sent with `Content-Encoding: gzip`, `br` or `zstd` are decompressed before handlers see them
+// A tiny compressed body can expand into gigabytes (a "zip bomb"), so extractors stop reading
+// once the decompressed body passes `decompression.max_size`, and the request fails with a 413
+
+use axum::{extract::DefaultBodyLimit, http::header, Router};
+use std::sync::Arc;
+use tower_http::{
+    compression::{
+        predicate::{NotForContentType, Predicate, SizeAbove},
+        CompressionLayer,
+    },
+    decompression::RequestDecompressionLayer,
+};
+
+use crate::config::Config;
+
+/// Adds compression and decompression to every route of `app`, configured from `config`.
+pub fn install(app: Router, config: &Config) -> Router {
+    let compress_when = SizeAbove::new(config.compression_min_size)
+        .and(ContentTypes(
+            config.compression_content_types.clone().into(),
+        ))
+        .and(NotForContentType::SSE)
+        .and(NotForContentType::GRPC);
+
+    // The body limit is read by axum's extractors, which only run once the body has been decompressed,
+    // so it limits the decompressed size (and the size of uncompressed bodies, which are their own decompressed size)
+    app.layer(DefaultBodyLimit::max(config.decompression_max_size))
+        .layer(RequestDecompressionLayer::new())
+        .layer(CompressionLayer::new().compress_when(compress_when))
+}
+
+/// Compresses responses whose content type is in the list.
+/// An entry like `text/*` matches every subtype, and parameters like `; charset=utf-8` are ignored.
+#[derive(Debug, Clone)]
+pub struct ContentTypes(pub Arc<[String]>);
+
+impl Predicate for ContentTypes {
+    fn should_compress<B>(&self, response: &axum::http::Response<B>) -> bool
+    where
+        B: axum::body::HttpBody,
+    {
+        let Some(content_type) = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+        self.0
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(kind) => essence
+                    .split_once('/')
+                    .is_some_and(|(essence_kind, _)| essence_kind.eq_ignore_ascii_case(kind)),
+                None => essence.eq_ignore_ascii_case(allowed),
+            })
+    }
+}
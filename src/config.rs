@@ -0,0 +1,899 @@
+// Typed configuration for the server
+// Every setting has a dotted key (like `port` or `log.format`) and can come from four places
+// From lowest to highest precedence:
+//   1. The built-in default
+//   2. A TOML file, given with `--config <path>` or the `CONFIG_FILE` environment variable
+//   3. An environment variable: the key uppercased, with `.` replaced by `_` (`log.format` -> `LOG_FORMAT`)
+//   4. A command line flag: the key with `.` and `_` replaced by `-` (`log.format` -> `--log-format <value>`)
+// Problems are collected while loading, so a bad deployment reports every wrong field at once
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use jsonwebtoken::Algorithm;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use time::{
+    format_description::well_known::Rfc3339,
+    macros::{datetime, format_description},
+    Date, OffsetDateTime,
+};
+
+use crate::{
+    access_log::AccessLogFormat,
+    cors::OriginPattern,
+    limits::RouteTimeout,
+    listener::ListenAddr,
+    logging::{self, LogFormat, StaticField},
+    rate_limit::{self, Quota, RateLimitBackend, RateLimitKey, RouteQuota, TrustedProxy},
+    security_headers::{self, ContentSecurityPolicy},
+    telemetry::OtlpProtocol,
+};
+
+/// The fully validated server configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `development` or `production`. Development sends the details of internal errors to clients.
+    pub environment: Environment,
+    /// Addresses to accept connections on.
+    /// Defaults to dual-stack `[::]:<port>`, with the port taken from the `port` key.
+    pub listen: Vec<ListenAddr>,
+    /// File permissions for Unix domain sockets, written in octal like `660`.
+    pub unix_socket_mode: u32,
+    /// PEM certificate chain and private key; when both are set, TCP listeners serve HTTPS.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// How often the certificate files are checked for changes.
+    pub tls_reload_interval: Duration,
+    /// Output format for logs: `full`, `compact`, `pretty` or `json`.
+    pub log_format: LogFormat,
+    /// `RUST_LOG`-style filter directives. Defaults to `RUST_LOG`, or `info` when that isn't set.
+    pub log_filter: String,
+    /// Service name added to every log event.
+    pub log_service: String,
+    /// Deployment id added to every log event. Defaults to `RAILWAY_DEPLOYMENT_ID`.
+    pub log_deployment_id: Option<String>,
+    /// Extra `key=value` fields added to every log event.
+    pub log_fields: Vec<StaticField>,
+    /// OTLP collector to export traces to, like `http://localhost:4317`. Export is disabled when this isn't set.
+    pub otel_endpoint: Option<String>,
+    /// Whether to talk to the collector over `grpc` or `http`.
+    pub otel_protocol: OtlpProtocol,
+    /// Fraction of new traces to sample, between 0 and 1.
+    pub otel_sampling_ratio: f64,
+    /// Access log format: `default`, `common`, `combined` or `off`.
+    pub access_log_format: AccessLogFormat,
+    /// Paths left out of the access log.
+    pub access_log_exclude: Vec<String>,
+    /// Postgres connection URL. No database pool is created when this isn't set.
+    pub database_url: Option<Secret<String>>,
+    /// Most connections the database pool opens.
+    pub database_max_connections: u32,
+    /// How long a request waits for a free database connection before failing.
+    pub database_acquire_timeout: Duration,
+    /// Timeout for requests made with the shared HTTP client.
+    pub http_client_timeout: Duration,
+    /// How long entries stay in the shared cache.
+    pub cache_ttl: Duration,
+    /// Most entries the shared cache holds.
+    pub cache_max_entries: usize,
+    /// Names of the enabled feature flags.
+    pub features: Vec<String>,
+    /// When version 1 of the API was deprecated, announced in a `Deprecation` header on its responses.
+    pub api_v1_deprecated: OffsetDateTime,
+    /// When version 1 of the API stops working. Until then, its responses announce the date in a `Sunset` header.
+    pub api_v1_sunset: Option<OffsetDateTime>,
+    /// Smallest response body, in bytes, worth compressing.
+    pub compression_min_size: u16,
+    /// Content types that are compressed, like `application/json` or `text/*`.
+    pub compression_content_types: Vec<String>,
+    /// Largest request body, in bytes, a handler reads once it is decompressed.
+    pub decompression_max_size: usize,
+    /// Origins that may call our API from a browser, like `https://app.example.com` or `https://*.example.com`.
+    pub cors_allowed_origins: Vec<OriginPattern>,
+    /// Methods cross-origin requests may use.
+    pub cors_allowed_methods: Vec<Method>,
+    /// Request headers cross-origin requests may send.
+    pub cors_allowed_headers: Vec<HeaderName>,
+    /// Whether cross-origin requests from allowed origins may include cookies and `Authorization` headers.
+    pub cors_allow_credentials: bool,
+    /// How long browsers may cache the answer to a preflight request.
+    pub cors_max_age: Duration,
+    /// Path prefixes any origin may read, without credentials.
+    pub cors_public_paths: Vec<String>,
+    /// Path prefixes no origin may read.
+    pub cors_private_paths: Vec<String>,
+    /// The rate limit for routes without one in `rate_limit_routes`, or `None` for no limit.
+    pub rate_limit_default: Option<Quota>,
+    /// Rate limits for the routes under path prefixes, like `/v1=100/1m` or `/healthz=off`.
+    pub rate_limit_routes: Vec<RouteQuota>,
+    /// What clients are told apart by: their IP address, API key or user.
+    pub rate_limit_key: RateLimitKey,
+    /// The header clients send their API key in.
+    pub rate_limit_api_key_header: HeaderName,
+    /// Proxies in front of us, whose `X-Forwarded-For` hops tell us the client's IP address.
+    pub rate_limit_trusted_proxies: Vec<TrustedProxy>,
+    /// Where rate limit state is kept: in memory, or in Redis to share it between replicas.
+    pub rate_limit_backend: RateLimitBackend,
+    /// Redis connection URL, for the `redis` backend.
+    pub rate_limit_redis_url: Option<Secret<String>>,
+    /// How long a request may take, unless its route has a timeout in `limits_route_timeouts`.
+    pub limits_request_timeout: Duration,
+    /// Timeouts for the routes under path prefixes, like `/v1=5s` or `/admin=off`.
+    pub limits_route_timeouts: Vec<RouteTimeout>,
+    /// Largest request body, in bytes, as sent.
+    pub limits_max_body_size: usize,
+    /// Most requests in flight at once. Any more are shed with a 503.
+    pub limits_max_in_flight: usize,
+    /// Longest request URI, in bytes.
+    pub limits_max_uri_length: usize,
+    /// Largest total size of a request's headers, in bytes.
+    pub limits_max_header_size: usize,
+    /// `Strict-Transport-Security` header value, or `None` to leave it out.
+    pub security_headers_hsts: Option<HeaderValue>,
+    /// `Referrer-Policy` header value, or `None` to leave it out.
+    pub security_headers_referrer_policy: Option<HeaderValue>,
+    /// `Permissions-Policy` header value, or `None` to leave it out.
+    pub security_headers_permissions_policy: Option<HeaderValue>,
+    /// `X-Frame-Options` header value, or `None` to leave it out.
+    pub security_headers_frame_options: Option<HeaderValue>,
+    /// The Content-Security-Policy for responses whose handler doesn't set one, or `None` for no policy.
+    pub security_headers_content_security_policy: Option<ContentSecurityPolicy>,
+    /// Shared secret for HS256 tokens. HS256 tokens are rejected when this isn't set.
+    pub auth_secret: Option<Secret<String>>,
+    /// Where the issuer publishes the public keys for RS256 and EdDSA tokens, as a JWKS.
+    pub auth_jwks_url: Option<String>,
+    /// How long a fetched JWKS is cached.
+    pub auth_jwks_ttl: Duration,
+    /// Algorithms tokens may be signed with.
+    pub auth_algorithms: Vec<Algorithm>,
+    /// The `iss` tokens must have. Any issuer is accepted when this isn't set.
+    pub auth_issuer: Option<String>,
+    /// Audiences, one of which tokens must have in `aud`. Any audience is accepted when this is empty.
+    pub auth_audience: Vec<String>,
+    /// How far our clock may be from the issuer's, when checking `exp` and `nbf`.
+    pub auth_leeway: Duration,
+    /// Whether `/docs` serves a Redoc page for our OpenAPI document.
+    pub openapi_docs: bool,
+    /// Whether 404 responses suggest known paths that are close to the requested one.
+    pub fallback_suggestions: bool,
+    /// Bearer token for the `/admin` routes. The routes are disabled when this isn't set.
+    pub admin_token: Option<Secret<String>>,
+    /// How long we keep accepting connections after a shutdown signal, while readiness checks fail.
+    pub shutdown_delay: Duration,
+    /// How long in-flight requests may take to finish once we stop accepting connections.
+    pub shutdown_timeout: Duration,
+}
+
+impl Config {
+    /// Loads the configuration from the process environment and command line arguments.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_vars(std::env::vars().collect(), std::env::args().skip(1))
+    }
+
+    /// Loads the configuration from these environment variables and command line arguments,
+    /// as `load` does from the process's own.
+    pub fn from_vars<I>(env: HashMap<String, String>, args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let sources = Sources::from_process(env, args.into_iter().map(Into::into));
+        Self::from_sources(sources)
+    }
+
+    /// Loads the configuration from command line flags alone, like `["--port", "8080"]`.
+    /// The process environment is ignored, so tests aren't affected by the environment they run in.
+    pub fn from_args<I>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        Self::from_vars(HashMap::new(), args)
+    }
+
+    fn from_sources(sources: Sources) -> Result<Self, ConfigError> {
+        let mut fields = Fields::new(&sources);
+
+        let port = fields.parse("port", 3000);
+        // Without `log.filter`, we use `RUST_LOG` like other Rust programs, which must be just as valid
+        let default_filter = match sources.env.get("RUST_LOG") {
+            Some(rust_log) if sources.get("log.filter").is_none() => {
+                let validated = logging::validate_filter(rust_log);
+                validated.unwrap_or_else(|error| {
+                    fields.problem(format!(
+                        "log.filter: {error} (got {rust_log:?} from RUST_LOG)"
+                    ));
+                    "info".into()
+                })
+            }
+            _ => "info".into(),
+        };
+        let config = Config {
+            environment: fields.parse("environment", Environment::Production),
+            listen: fields.list("listen", vec![ListenAddr::dual_stack(port)]),
+            unix_socket_mode: fields.get_with("unix_socket_mode", 0o660, |value| {
+                u32::from_str_radix(value, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or_else(|| "expected octal file permissions, like 660".to_owned())
+            }),
+            tls_cert: fields.optional("tls.cert"),
+            tls_key: fields.optional("tls.key"),
+            tls_reload_interval: fields.get_with(
+                "tls.reload_interval",
+                Duration::from_secs(10),
+                parse_interval,
+            ),
+            log_format: fields.parse("log.format", LogFormat::Full),
+            log_filter: fields.get_with("log.filter", default_filter, logging::validate_filter),
+            log_service: fields.parse("log.service", env!("CARGO_PKG_NAME").to_owned()),
+            log_deployment_id: fields
+                .optional("log.deployment_id")
+                .or_else(|| sources.env.get("RAILWAY_DEPLOYMENT_ID").cloned()),
+            log_fields: fields.list("log.fields", Vec::new()),
+            otel_endpoint: fields.optional("otel.endpoint"),
+            otel_protocol: fields.parse("otel.protocol", OtlpProtocol::Grpc),
+            otel_sampling_ratio: fields.get_with("otel.sampling_ratio", 1.0, |value| {
+                value
+                    .parse()
+                    .ok()
+                    .filter(|ratio| (0.0..=1.0).contains(ratio))
+                    .ok_or_else(|| "expected a number between 0 and 1".to_owned())
+            }),
+            access_log_format: fields.parse("access_log.format", AccessLogFormat::Default),
+            access_log_exclude: fields.list(
+                "access_log.exclude",
+                vec![
+                    "/healthz".to_owned(),
+                    "/readyz".to_owned(),
+                    "/metrics".to_owned(),
+                ],
+            ),
+            database_url: fields.optional("database.url"),
+            database_max_connections: fields.parse("database.max_connections", 10),
+            database_acquire_timeout: fields.get_with(
+                "database.acquire_timeout",
+                Duration::from_secs(5),
+                parse_duration,
+            ),
+            http_client_timeout: fields.get_with(
+                "http_client.timeout",
+                Duration::from_secs(10),
+                parse_duration,
+            ),
+            cache_ttl: fields.get_with("cache.ttl", Duration::from_secs(60), parse_duration),
+            cache_max_entries: fields.parse("cache.max_entries", 10_000),
+            features: fields.list("features", Vec::new()),
+            api_v1_deprecated: fields.get_with(
+                "api.v1_deprecated",
+                datetime!(2026-10-15 0:00 UTC),
+                parse_date,
+            ),
+            api_v1_sunset: fields
+                .get_with("api.v1_sunset", None, |value| parse_date(value).map(Some)),
+            compression_min_size: fields.parse("compression.min_size", 1024),
+            compression_content_types: fields.list(
+                "compression.content_types",
+                vec![
+                    "application/json".to_owned(),
+                    "application/problem+json".to_owned(),
+                    "text/*".to_owned(),
+                ],
+            ),
+            decompression_max_size: fields.get_with(
+                "decompression.max_size",
+                2 * 1024 * 1024,
+                parse_size,
+            ),
+            cors_allowed_origins: fields.list("cors.allowed_origins", Vec::new()),
+            cors_allowed_methods: fields.list(
+                "cors.allowed_methods",
+                vec![
+                    Method::GET,
+                    Method::HEAD,
+                    Method::POST,
+                    Method::PUT,
+                    Method::PATCH,
+                    Method::DELETE,
+                ],
+            ),
+            cors_allowed_headers: fields.list(
+                "cors.allowed_headers",
+                vec![header::AUTHORIZATION, header::CONTENT_TYPE],
+            ),
+            cors_allow_credentials: fields.parse("cors.allow_credentials", false),
+            cors_max_age: fields.get_with(
+                "cors.max_age",
+                Duration::from_secs(10 * 60),
+                parse_duration,
+            ),
+            cors_public_paths: fields.list("cors.public_paths", vec!["/openapi.json".to_owned()]),
+            cors_private_paths: fields.list(
+                "cors.private_paths",
+                vec!["/admin".to_owned(), "/metrics".to_owned()],
+            ),
+            rate_limit_default: fields.get_with(
+                "rate_limit.default",
+                Some(Quota {
+                    limit: 100,
+                    period: Duration::from_secs(60),
+                }),
+                rate_limit::parse_quota,
+            ),
+            rate_limit_routes: fields.list(
+                "rate_limit.routes",
+                ["/healthz", "/readyz", "/metrics"]
+                    .into_iter()
+                    .map(|prefix| RouteQuota {
+                        prefix: prefix.to_owned(),
+                        quota: None,
+                    })
+                    .collect(),
+            ),
+            rate_limit_key: fields.parse("rate_limit.key", RateLimitKey::Ip),
+            rate_limit_api_key_header: fields.parse(
+                "rate_limit.api_key_header",
+                HeaderName::from_static("x-api-key"),
+            ),
+            rate_limit_trusted_proxies: fields.list("rate_limit.trusted_proxies", Vec::new()),
+            rate_limit_backend: fields.parse("rate_limit.backend", RateLimitBackend::Memory),
+            rate_limit_redis_url: fields.optional("rate_limit.redis_url"),
+            limits_request_timeout: fields.get_with(
+                "limits.request_timeout",
+                Duration::from_secs(30),
+                parse_duration,
+            ),
+            limits_route_timeouts: fields.list("limits.route_timeouts", Vec::new()),
+            limits_max_body_size: fields.get_with(
+                "limits.max_body_size",
+                2 * 1024 * 1024,
+                parse_size,
+            ),
+            limits_max_in_flight: fields.parse("limits.max_in_flight", 1024),
+            limits_max_uri_length: fields.get_with("limits.max_uri_length", 8 * 1024, parse_size),
+            limits_max_header_size: fields.get_with(
+                "limits.max_header_size",
+                16 * 1024,
+                parse_size,
+            ),
+            security_headers_hsts: fields.get_with(
+                "security_headers.hsts",
+                Some(HeaderValue::from_static(
+                    "max-age=31536000; includeSubDomains",
+                )),
+                security_headers::parse_header,
+            ),
+            security_headers_referrer_policy: fields.get_with(
+                "security_headers.referrer_policy",
+                Some(HeaderValue::from_static("no-referrer")),
+                security_headers::parse_header,
+            ),
+            security_headers_permissions_policy: fields.get_with(
+                "security_headers.permissions_policy",
+                Some(HeaderValue::from_static(
+                    "camera=(), geolocation=(), microphone=(), payment=(), usb=()",
+                )),
+                security_headers::parse_header,
+            ),
+            security_headers_frame_options: fields.get_with(
+                "security_headers.frame_options",
+                Some(HeaderValue::from_static("DENY")),
+                security_headers::parse_header,
+            ),
+            security_headers_content_security_policy: fields.get_with(
+                "security_headers.content_security_policy",
+                Some(ContentSecurityPolicy::strict()),
+                security_headers::parse_policy,
+            ),
+            auth_secret: fields.optional("auth.secret"),
+            auth_jwks_url: fields.optional("auth.jwks_url"),
+            auth_jwks_ttl: fields.get_with(
+                "auth.jwks_ttl",
+                Duration::from_secs(10 * 60),
+                parse_duration,
+            ),
+            auth_algorithms: fields.list(
+                "auth.algorithms",
+                vec![Algorithm::HS256, Algorithm::RS256, Algorithm::EdDSA],
+            ),
+            auth_issuer: fields.optional("auth.issuer"),
+            auth_audience: fields.list("auth.audience", Vec::new()),
+            auth_leeway: fields.get_with("auth.leeway", Duration::from_secs(60), parse_duration),
+            openapi_docs: fields.parse("openapi.docs", true),
+            fallback_suggestions: fields.parse("fallback.suggestions", true),
+            admin_token: fields.optional("admin.token"),
+            shutdown_delay: fields.get_with(
+                "shutdown_delay",
+                Duration::from_secs(5),
+                parse_duration,
+            ),
+            shutdown_timeout: fields.get_with(
+                "shutdown_timeout",
+                Duration::from_secs(30),
+                parse_duration,
+            ),
+        };
+
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            fields.problem("tls.cert and tls.key must be given together");
+        }
+        if config.rate_limit_backend == RateLimitBackend::Redis
+            && config.rate_limit_redis_url.is_none()
+        {
+            fields.problem("rate_limit.redis_url is required for the redis backend");
+        }
+        // HS256 keys shorter than its 256 bit hash can be brute forced
+        if config
+            .auth_secret
+            .as_ref()
+            .is_some_and(|secret| secret.expose().len() < 32)
+        {
+            fields.problem("auth.secret must be at least 32 bytes");
+        }
+        if config.limits_max_in_flight == 0 {
+            fields.problem("limits.max_in_flight must be at least 1");
+        }
+        // Browsers refuse credentials on responses any origin can read
+        if config.cors_allow_credentials
+            && config.cors_allowed_origins.contains(&OriginPattern::Any)
+        {
+            fields
+                .problem("cors.allow_credentials can't be combined with cors.allowed_origins = *");
+        }
+
+        fields.finish()?;
+        Ok(config)
+    }
+}
+
+/// The kind of deployment we are running in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Development,
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "development" => Ok(Environment::Development),
+            "production" => Ok(Environment::Production),
+            _ => Err("expected development or production".into()),
+        }
+    }
+}
+
+/// A value that must never end up in logs, such as a password or token.
+///
+/// `Debug` and `Display` print `[redacted]`; use [`Secret::expose`] to read the value.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl<T: FromStr> FromStr for Secret<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Secret)
+    }
+}
+
+/// Every problem found while loading the configuration.
+/// When `--help` was given, it holds our usage instead, which isn't a failure.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl ConfigError {
+    /// Whether this is the answer to `--help`, rather than a problem.
+    pub fn is_help(&self) -> bool {
+        self.help.is_some()
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(help) = &self.help {
+            return f.write_str(help);
+        }
+        writeln!(
+            f,
+            "invalid configuration ({} problem(s)):",
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Where a raw value came from, used in error messages
+#[derive(Debug, Clone)]
+enum Origin {
+    File(PathBuf),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::File(path) => write!(f, "config file {}", path.display()),
+            Origin::Env(name) => write!(f, "environment variable {name}"),
+            Origin::Flag(name) => write!(f, "flag --{name}"),
+        }
+    }
+}
+
+// The raw, unparsed values from every source
+struct Sources {
+    env: HashMap<String, String>,
+    file: Option<(PathBuf, HashMap<String, String>)>,
+    flags: HashMap<String, String>,
+    // Problems with the sources themselves, like a missing file or a flag without a value
+    problems: Vec<String>,
+    // Whether `--help` was given, in which case we describe our settings instead of loading them
+    help: bool,
+}
+
+impl Sources {
+    fn from_process(env: HashMap<String, String>, args: impl Iterator<Item = String>) -> Self {
+        let mut problems = Vec::new();
+        let mut flags = HashMap::new();
+
+        let mut help = false;
+
+        // Flags are either `--name value` or `--name=value`
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                help = true;
+                continue;
+            }
+            let Some(flag) = arg.strip_prefix("--") else {
+                problems.push(format!("unexpected argument {arg:?}"));
+                continue;
+            };
+            match flag.split_once('=') {
+                Some((name, value)) => {
+                    flags.insert(name.to_owned(), value.to_owned());
+                }
+                None => match args.next_if(|next| !next.starts_with("--")) {
+                    Some(value) => {
+                        flags.insert(flag.to_owned(), value);
+                    }
+                    None => problems.push(format!("flag --{flag} is missing a value")),
+                },
+            }
+        }
+
+        let path = flags
+            .remove("config")
+            .or_else(|| env.get("CONFIG_FILE").cloned())
+            .map(PathBuf::from);
+        let file = path.and_then(|path| match read_file(&path) {
+            Ok(values) => Some((path, values)),
+            Err(problem) => {
+                problems.push(format!("config file {}: {problem}", path.display()));
+                None
+            }
+        });
+
+        Self {
+            env,
+            file,
+            flags,
+            problems,
+            help,
+        }
+    }
+
+    // Looks a key up in every source, highest precedence first
+    fn get(&self, key: &str) -> Option<(&str, Origin)> {
+        let flag = key.replace(['.', '_'], "-");
+        if let Some(value) = self.flags.get(&flag) {
+            return Some((value, Origin::Flag(flag)));
+        }
+
+        let env = key.replace('.', "_").to_uppercase();
+        if let Some(value) = self.env.get(&env) {
+            return Some((value, Origin::Env(env)));
+        }
+
+        let (path, values) = self.file.as_ref()?;
+        let value = values.get(key)?;
+        Some((value, Origin::File(path.clone())))
+    }
+}
+
+// Reads a TOML file and flattens it into dotted keys
+// Arrays become comma separated lists, matching how lists are written in environment variables
+fn read_file(path: &PathBuf) -> Result<HashMap<String, String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let table: toml::Table = toml::from_str(&contents).map_err(|error| error.to_string())?;
+
+    fn flatten(prefix: &str, value: &toml::Value, out: &mut HashMap<String, String>) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table {
+                    let key = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{prefix}.{key}")
+                    };
+                    flatten(&key, value, out);
+                }
+            }
+            toml::Value::Array(items) => {
+                let items: Vec<String> = items.iter().map(scalar).collect();
+                out.insert(prefix.to_owned(), items.join(","));
+            }
+            value => {
+                out.insert(prefix.to_owned(), scalar(value));
+            }
+        }
+    }
+
+    fn scalar(value: &toml::Value) -> String {
+        match value {
+            toml::Value::String(string) => string.clone(),
+            value => value.to_string(),
+        }
+    }
+
+    let mut values = HashMap::new();
+    flatten("", &toml::Value::Table(table), &mut values);
+    Ok(values)
+}
+
+// Parses fields one at a time, recording problems instead of stopping at the first one
+struct Fields<'a> {
+    sources: &'a Sources,
+    problems: Vec<String>,
+    known: HashSet<String>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(sources: &'a Sources) -> Self {
+        Self {
+            sources,
+            problems: sources.problems.clone(),
+            known: HashSet::new(),
+        }
+    }
+
+    fn parse<T>(&mut self, key: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get_with(key, default, |value| {
+            value.parse().map_err(|error: T::Err| error.to_string())
+        })
+    }
+
+    // Fields without a default, which are `None` unless given
+    fn optional<T>(&mut self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get_with(key, None, |value| {
+            value
+                .parse()
+                .map(Some)
+                .map_err(|error: T::Err| error.to_string())
+        })
+    }
+
+    // Lists are comma separated, every item is parsed on its own
+    fn list<T>(&mut self, key: &str, default: Vec<T>) -> Vec<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get_with(key, default, |value| {
+            let items = value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| {
+                    item.parse()
+                        .map_err(|error: T::Err| format!("{item:?}: {error}"))
+                })
+                .collect::<Result<Vec<T>, String>>()?;
+            if items.is_empty() {
+                return Err("expected at least one item".into());
+            }
+            Ok(items)
+        })
+    }
+
+    fn get_with<T>(
+        &mut self,
+        key: &str,
+        default: T,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> T {
+        self.known.insert(key.to_owned());
+        let Some((value, origin)) = self.sources.get(key) else {
+            return default;
+        };
+        match parse(value.trim()) {
+            Ok(value) => value,
+            Err(error) => {
+                self.problems
+                    .push(format!("{key}: {error} (got {value:?} from {origin})"));
+                default
+            }
+        }
+    }
+
+    // Records a problem that involves more than one field
+    fn problem(&mut self, problem: impl Into<String>) {
+        self.problems.push(problem.into());
+    }
+
+    // Reports any keys that were given but don't match a field, which usually means a typo
+    fn finish(mut self) -> Result<(), ConfigError> {
+        let mut unknown: Vec<String> = self
+            .sources
+            .flags
+            .keys()
+            .filter(|flag| {
+                !self
+                    .known
+                    .iter()
+                    .any(|key| key.replace(['.', '_'], "-") == **flag)
+            })
+            .map(|flag| format!("unknown flag --{flag}"))
+            .collect();
+        if let Some((path, values)) = &self.sources.file {
+            unknown.extend(
+                values
+                    .keys()
+                    .filter(|key| !self.known.contains(*key))
+                    .map(|key| format!("unknown key {key:?} in config file {}", path.display())),
+            );
+        }
+        unknown.sort();
+        self.problems.extend(unknown);
+
+        if self.sources.help {
+            return Err(ConfigError {
+                problems: Vec::new(),
+                help: Some(usage(&self.known)),
+            });
+        }
+        if self.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError {
+                problems: self.problems,
+                help: None,
+            })
+        }
+    }
+}
+
+// Every setting, with its flag and environment variable
+fn usage(known: &HashSet<String>) -> String {
+    let mut keys: Vec<&String> = known.iter().collect();
+    keys.sort();
+    let mut usage = String::from(
+        "Usage: rust-starter [--config <path>] [--<setting> <value>]...\n\nSettings:\n",
+    );
+    for key in keys {
+        let flag = format!("--{}", key.replace(['.', '_'], "-"));
+        let env = key.replace('.', "_").to_uppercase();
+        usage.push_str(&format!("  {flag:<44} {env}\n"));
+    }
+    usage
+}
+
+// Dates are written in RFC 3339, like `2027-04-15T00:00:00Z`, or as just a day, which means midnight UTC
+fn parse_date(value: &str) -> Result<OffsetDateTime, String> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .or_else(|_| {
+            Date::parse(value, format_description!("[year]-[month]-[day]"))
+                .map(|date| date.midnight().assume_utc())
+        })
+        .map_err(|_| "expected a date like 2027-04-15 or 2027-04-15T00:00:00Z".to_owned())
+}
+
+/// Whether the path prefix `prefix`, from a list in our config, covers `path`.
+/// Prefixes match whole segments: `/v1` covers `/v1` and `/v1/complex`, but not `/v10`.
+pub fn path_covers(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Sizes are written as a number of bytes, with an optional binary unit: `512`, `64KiB`, `2MiB` or `1GiB`
+fn parse_size(value: &str) -> Result<usize, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: usize = number
+        .parse()
+        .map_err(|_| "expected a size like 512, 64KiB or 2MiB".to_owned())?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1024,
+        "MiB" => 1024 * 1024,
+        "GiB" => 1024 * 1024 * 1024,
+        unit => {
+            return Err(format!(
+                "unknown size unit {unit:?}, expected B, KiB, MiB or GiB"
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| "size is too large".to_owned())
+}
+
+// Intervals are durations between things we do over and over, so they can't be zero
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    match parse_duration(value)? {
+        interval if interval.is_zero() => Err("the interval must be more than zero".into()),
+        interval => Ok(interval),
+    }
+}
+
+// Durations are written as a number with an optional unit: `500ms`, `30s`, `5m` or `1h`
+// A bare number is taken as seconds
+pub(crate) fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| "expected a duration like 30s, 500ms or 5m".to_owned())?;
+    if unit.trim() == "ms" {
+        return Ok(Duration::from_millis(number));
+    }
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        unit => {
+            return Err(format!(
+                "unknown duration unit {unit:?}, expected ms, s, m or h"
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| "duration is too long".to_owned())
+}
@@ -0,0 +1,192 @@
+// Cross-origin resource sharing (CORS), with `tower-http`'s `CorsLayer`
+// Browsers only let a frontend on another origin read our responses (or send anything but simple requests)
+// when we answer with the right `Access-Control-*` headers, including on the preflight `OPTIONS` request they send first
+//
+// Every path gets one of three policies:
+//   public      any origin may read it, without credentials; for documents anyone may fetch, like `/openapi.json`
+//   private     no origin may; for routes only our own tooling calls, like `/admin` and `/metrics`
+//   configured  the origins in `cors.allowed_origins`, with or without credentials; everything else
+// Allowed origins are exact (`https://app.example.com`), wildcard subdomains (`https://*.example.com`), or `*`
+
+use axum::{
+    http::{request::Parts, HeaderName, HeaderValue, Method},
+    Router,
+};
+use std::{fmt, str::FromStr, sync::Arc};
+use tower_http::cors::{AllowCredentials, AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::{
+    config::{path_covers, Config},
+    request_id::X_REQUEST_ID,
+};
+
+/// An origin, or a pattern of origins, that may make cross-origin requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OriginPattern {
+    /// Any origin, written `*`.
+    Any,
+    /// Exactly this origin, like `https://app.example.com`.
+    Exact(String),
+    /// Any subdomain, written `https://*.example.com`.
+    /// `prefix` is the scheme (`https://`), and `suffix` the parent domain and port (`.example.com`).
+    Subdomains { prefix: String, suffix: String },
+}
+
+impl OriginPattern {
+    /// Whether `origin`, as sent in the `Origin` header, matches.
+    pub fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => origin == *exact,
+            OriginPattern::Subdomains { prefix, suffix } => origin
+                .strip_prefix(prefix.as_str())
+                .and_then(|rest| rest.strip_suffix(suffix.as_str()))
+                .is_some_and(|subdomain| {
+                    !subdomain.is_empty()
+                        && subdomain
+                            .split('.')
+                            .all(|label| !label.is_empty() && label.chars().all(is_label_char))
+                }),
+        }
+    }
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-'
+}
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        let origin = s.trim_end_matches('/').to_ascii_lowercase();
+        let Some((scheme, host)) = origin.split_once("://") else {
+            return Err(format!(
+                "expected an origin like https://app.example.com, https://*.example.com or *, got {s:?}"
+            ));
+        };
+        if scheme.is_empty() || host.is_empty() || host.contains('/') {
+            return Err(format!("{s:?} isn't an origin"));
+        }
+        match host.strip_prefix('*') {
+            Some(parent) if parent.starts_with('.') && !parent.contains('*') => {
+                Ok(OriginPattern::Subdomains {
+                    prefix: format!("{scheme}://"),
+                    suffix: parent.to_owned(),
+                })
+            }
+            _ if host.contains('*') => Err(format!(
+                "{s:?}: wildcards are only allowed for a whole subdomain, like https://*.example.com"
+            )),
+            _ => Ok(OriginPattern::Exact(origin)),
+        }
+    }
+}
+
+impl fmt::Display for OriginPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginPattern::Any => f.write_str("*"),
+            OriginPattern::Exact(origin) => f.write_str(origin),
+            OriginPattern::Subdomains { prefix, suffix } => write!(f, "{prefix}*{suffix}"),
+        }
+    }
+}
+
+/// Which CORS policy applies to a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Public,
+    Private,
+    Configured,
+}
+
+/// The CORS settings from our config.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    pub allowed_origins: Vec<OriginPattern>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    pub allow_credentials: bool,
+    pub max_age: std::time::Duration,
+    /// Path prefixes that any origin may read, like `/openapi.json`.
+    pub public_paths: Vec<String>,
+    /// Path prefixes that no origin may read, like `/admin`.
+    pub private_paths: Vec<String>,
+}
+
+impl Cors {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            allowed_origins: config.cors_allowed_origins.clone(),
+            allowed_methods: config.cors_allowed_methods.clone(),
+            allowed_headers: config.cors_allowed_headers.clone(),
+            allow_credentials: config.cors_allow_credentials,
+            max_age: config.cors_max_age,
+            public_paths: config.cors_public_paths.clone(),
+            private_paths: config.cors_private_paths.clone(),
+        }
+    }
+
+    /// The policy for `path`. Private paths win over public ones, so a mistake can't expose them.
+    pub fn access(&self, path: &str) -> Access {
+        let covers = |prefix: &String| path_covers(prefix, path);
+        if self.private_paths.iter().any(covers) {
+            Access::Private
+        } else if self.public_paths.iter().any(covers) {
+            Access::Public
+        } else {
+            Access::Configured
+        }
+    }
+
+    fn allows_origin(&self, origin: &HeaderValue, parts: &Parts) -> bool {
+        let Ok(origin) = origin.to_str() else {
+            return false;
+        };
+        match self.access(parts.uri.path()) {
+            Access::Public => true,
+            Access::Private => false,
+            Access::Configured => self
+                .allowed_origins
+                .iter()
+                .any(|pattern| pattern.matches(origin)),
+        }
+    }
+
+    /// The `CorsLayer` for these settings.
+    pub fn layer(self) -> CorsLayer {
+        let allowed_methods = self.allowed_methods.clone();
+        let allowed_headers = self.allowed_headers.clone();
+        let max_age = self.max_age;
+        let cors = Arc::new(self);
+        let origins = cors.clone();
+
+        CorsLayer::new()
+            // The allowed origin is mirrored back, so a wildcard pattern never turns into `Access-Control-Allow-Origin: *`
+            .allow_origin(AllowOrigin::predicate(move |origin, parts| {
+                origins.allows_origin(origin, parts)
+            }))
+            // Public paths never allow credentials, since any origin can read them
+            .allow_credentials(AllowCredentials::predicate(move |origin, parts| {
+                cors.allow_credentials
+                    && cors.access(parts.uri.path()) == Access::Configured
+                    && cors.allows_origin(origin, parts)
+            }))
+            .allow_methods(AllowMethods::list(allowed_methods))
+            .allow_headers(AllowHeaders::list(allowed_headers))
+            // Frontends can read our request ids, to include them in bug reports
+            .expose_headers([X_REQUEST_ID.clone()])
+            .max_age(max_age)
+    }
+}
+
+/// Adds CORS headers, and answers preflight requests, for every route of `app`.
+/// This goes outside the fallbacks, so preflights for any known path are answered instead of getting a 405.
+pub fn install(app: Router, config: &Config) -> Router {
+    app.layer(Cors::from_config(config).layer())
+}
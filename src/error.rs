@@ -0,0 +1,187 @@
+// A single error type for our handlers
+// Every `AppError` becomes an RFC 9457 `application/problem+json` response, like:
+//   {"type": "about:blank", "title": "Not Found", "status": 404, "detail": "no such user", "request_id": "..."}
+// Internal errors are logged with their full cause chain, but in production clients only see a generic message
+// Whether they see more is up to each app's `middleware`, so apps with different configs can run side by side
+// Any `std::error::Error` converts into an internal error, so handlers can use `?` freely
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Request, State,
+    },
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::error::Error;
+use utoipa::ToSchema;
+
+use crate::request_id::RequestId;
+
+tokio::task_local! {
+    // Whether internal error details are included in responses to the request being handled
+    static EXPOSE_INTERNAL_ERRORS: bool;
+}
+
+/// Middleware that decides whether the details of internal errors are sent to clients,
+/// for every error turned into a response while handling the request.
+/// This should only be turned on in development.
+pub async fn middleware(State(expose): State<bool>, request: Request, next: Next) -> Response {
+    EXPOSE_INTERNAL_ERRORS
+        .scope(expose, next.run(request))
+        .await
+}
+
+/// An error that can be returned from a handler.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    /// Any other status, with a detail message for the client.
+    Status(StatusCode, String),
+    /// Something went wrong on our side. The cause is logged, and only shown to clients in development.
+    Internal(Box<dyn Error + Send + Sync>),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Status(status, _) => *status,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// `AppError` deliberately doesn't implement `Error` itself, otherwise this would conflict with `From<T> for T`
+impl<E> From<E> for AppError
+where
+    E: Error + Send + Sync + 'static,
+{
+    fn from(error: E) -> Self {
+        let error: Box<dyn Error + Send + Sync> = Box::new(error);
+        // Extractor rejections are the client's fault, so they keep their status and message
+        match client_error(error.as_ref()) {
+            Some((status, detail)) => AppError::Status(status, detail),
+            None => AppError::Internal(error),
+        }
+    }
+}
+
+// The status and message of the extractor rejections handlers usually deal with
+fn client_error(error: &(dyn Error + 'static)) -> Option<(StatusCode, String)> {
+    macro_rules! rejections {
+        ($($rejection:ty),*) => {
+            $(
+                if let Some(rejection) = error.downcast_ref::<$rejection>() {
+                    return Some((rejection.status(), rejection.body_text()));
+                }
+            )*
+        };
+    }
+
+    rejections!(JsonRejection, PathRejection, QueryRejection);
+    None
+}
+
+/// An RFC 9457 problem details body.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Problem {
+    /// A URI identifying the kind of problem; `about:blank` when the status says it all.
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    pub kind: String,
+    /// A short summary of the kind of problem.
+    #[schema(example = "Not Found")]
+    pub title: String,
+    /// The HTTP status code.
+    #[schema(example = 404)]
+    pub status: u16,
+    /// An explanation of this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The id of the request, to quote when reporting the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Extension members, serialized next to the standard ones.
+    #[serde(flatten)]
+    #[schema(ignore)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Problem {
+    /// A problem that is fully described by its status code, so it uses the `about:blank` type.
+    pub fn new(status: StatusCode, detail: Option<String>) -> Self {
+        Self {
+            kind: "about:blank".into(),
+            title: status.canonical_reason().unwrap_or("Unknown Error").into(),
+            status: status.as_u16(),
+            detail,
+            request_id: RequestId::current().map(|id| id.to_string()),
+            extensions: serde_json::Map::new(),
+        }
+    }
+
+    /// Adds an extension member, like a list of validation errors.
+    pub fn with(mut self, key: &str, value: impl Serialize) -> Self {
+        // Our extension values are plain data, which always serializes
+        if let Ok(value) = serde_json::to_value(value) {
+            self.extensions.insert(key.to_owned(), value);
+        }
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = (status, Json(self)).into_response();
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        response
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let detail = match self {
+            AppError::BadRequest(detail)
+            | AppError::Unauthorized(detail)
+            | AppError::Status(_, detail) => detail,
+            AppError::Internal(error) => {
+                let chain = cause_chain(error.as_ref());
+                tracing::error!(error = %chain, "Internal server error");
+                // Outside of our middleware, like in a task of its own, we err on the side of caution
+                if EXPOSE_INTERNAL_ERRORS
+                    .try_with(|expose| *expose)
+                    .unwrap_or(false)
+                {
+                    chain
+                } else {
+                    "An unexpected error occurred".into()
+                }
+            }
+        };
+
+        Problem::new(status, Some(detail)).into_response()
+    }
+}
+
+// Joins an error and all of its sources, like `failed to load user: connection refused`
+fn cause_chain(error: &(dyn Error + 'static)) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push_str(": ");
+        chain.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    chain
+}
@@ -0,0 +1,137 @@
+// Responses for requests that don't match any route
+// Instead of axum's empty defaults, unknown paths get a problem+json 404, and known paths with the wrong method
+// get a problem+json 405 (axum adds the `Allow` header listing the methods the path does support)
+// A 404 can also suggest known paths that are close to the requested one, so `/helthz` points to `/healthz`
+// The known paths come from our OpenAPI document, so new routes are suggested without any extra work
+// Every unmatched request is counted in `http_unmatched_requests_total`, so broken clients show up on dashboards
+
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Router,
+};
+use std::sync::Arc;
+use utoipa::openapi::{Deprecated, OpenApi};
+
+use crate::{error::Problem, metrics::Metrics};
+
+// At most this many suggestions are sent back
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Settings for the fallback handlers.
+pub struct Fallback {
+    /// Paths that may be suggested for near misses. Empty when suggestions are turned off.
+    pub known_paths: Vec<String>,
+    pub metrics: Metrics,
+}
+
+/// Adds the 404 and 405 fallbacks to `app`.
+/// This must be called after every route is added, since the 405 fallback only applies to existing routes.
+pub fn install(app: Router, fallback: Fallback) -> Router {
+    let fallback = Arc::new(fallback);
+    let method_not_allowed = fallback.clone();
+    app.fallback(move |request: Request| not_found(fallback.clone(), request))
+        .method_not_allowed_fallback(move |request: Request| {
+            self::method_not_allowed(method_not_allowed.clone(), request)
+        })
+}
+
+/// The paths from our OpenAPI document that are worth suggesting: those anyone can `GET`, that aren't deprecated.
+/// The root path is left out, since it is a single character away from every other short path.
+pub fn public_paths(document: &OpenApi) -> Vec<String> {
+    document
+        .paths
+        .paths
+        .iter()
+        .filter(|(path, item)| {
+            *path != "/"
+                && item.get.as_ref().is_some_and(|operation| {
+                    operation.security.is_none() && operation.deprecated != Some(Deprecated::True)
+                })
+        })
+        .map(|(path, _)| path.clone())
+        .collect()
+}
+
+async fn not_found(fallback: Arc<Fallback>, request: Request) -> Response {
+    let path = request.uri().path();
+    tracing::debug!(method = %request.method(), path, "No route matched the request");
+    fallback
+        .metrics
+        .record_unmatched(request.method(), "not_found");
+
+    let suggestions = suggest(path, &fallback.known_paths);
+    let mut problem = Problem::new(
+        StatusCode::NOT_FOUND,
+        Some(format!("no route matches {path}")),
+    );
+    if !suggestions.is_empty() {
+        problem = problem.with("suggestions", suggestions);
+    }
+    problem.into_response()
+}
+
+async fn method_not_allowed(fallback: Arc<Fallback>, request: Request) -> Response {
+    let method = request.method();
+    let path = request.uri().path();
+    tracing::debug!(%method, path, "Method not allowed for the route");
+    fallback
+        .metrics
+        .record_unmatched(method, "method_not_allowed");
+
+    Problem::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        Some(format!("{method} is not allowed for {path}")),
+    )
+    .into_response()
+}
+
+// Known paths within a small edit distance of `path`, closest first
+// Case and trailing slashes are ignored, so `/V2/Complex/` suggests `/v2/complex`
+fn suggest<'a>(path: &str, known_paths: &'a [String]) -> Vec<&'a str> {
+    let normalized = normalize(path);
+    // Short paths get less leeway, otherwise `/a` would suggest every one-letter route
+    let max_distance = (normalized.chars().count() / 4).clamp(1, 3);
+
+    let mut candidates: Vec<(usize, &str)> = known_paths
+        .iter()
+        .map(|known| {
+            (
+                edit_distance(&normalized, &normalize(known)),
+                known.as_str(),
+            )
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, known)| known)
+        .collect()
+}
+
+fn normalize(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        "/".to_owned()
+    } else {
+        trimmed.to_lowercase()
+    }
+}
+
+// The Levenshtein distance: how many characters must be inserted, removed or replaced to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
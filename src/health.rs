@@ -0,0 +1,182 @@
+// Liveness and readiness probes
+// `/healthz` answers "is the process alive?" and never touches dependencies
+// `/readyz` answers "can we serve traffic right now?" by running every registered `HealthCheck`
+
+use async_trait::async_trait;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use crate::shutdown::Shutdown;
+
+// A check that takes longer than this is reported as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A dependency that must be healthy for the server to be ready.
+///
+/// Components implement this and register themselves with [`HealthRegistry::register`].
+#[async_trait]
+pub trait HealthCheck: Send + Sync + 'static {
+    /// Name shown in the readiness report.
+    fn name(&self) -> &str;
+
+    /// Whether a failure of this check makes the whole server "not ready".
+    /// Non-critical checks are reported, but don't fail the probe.
+    fn critical(&self) -> bool {
+        true
+    }
+
+    /// Runs the check, returning a human readable reason on failure.
+    async fn check(&self) -> Result<(), String>;
+}
+
+/// The set of checks consulted by `/readyz`.
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    checks: Vec<Arc<dyn HealthCheck>>,
+}
+
+impl HealthRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, check: impl HealthCheck) -> &mut Self {
+        self.checks.push(Arc::new(check));
+        self
+    }
+
+    /// Runs every check concurrently and collects the results.
+    pub async fn report(&self) -> ReadinessReport {
+        let checks =
+            futures::future::join_all(self.checks.iter().map(|check| run(check.as_ref()))).await;
+        let ready = checks
+            .iter()
+            .all(|check| !check.critical || check.status == Status::Pass);
+
+        ReadinessReport {
+            status: if ready { Status::Pass } else { Status::Fail },
+            checks,
+        }
+    }
+}
+
+async fn run(check: &dyn HealthCheck) -> CheckReport {
+    let started = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check.check()).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}ms", CHECK_TIMEOUT.as_millis())),
+    };
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    if let Err(error) = &result {
+        tracing::warn!(check = check.name(), %error, "Health check failed");
+    }
+
+    CheckReport {
+        name: check.name().to_owned(),
+        critical: check.critical(),
+        status: if result.is_ok() {
+            Status::Pass
+        } else {
+            Status::Fail
+        },
+        latency_ms,
+        error: result.err(),
+    }
+}
+
+#[derive(Serialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+}
+
+/// The body of `/healthz`.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct LivenessReport {
+    pub status: Status,
+}
+
+/// The body of `/readyz`.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct ReadinessReport {
+    pub status: Status,
+    pub checks: Vec<CheckReport>,
+}
+
+#[derive(Serialize, ToSchema, Debug)]
+pub struct CheckReport {
+    pub name: String,
+    pub critical: bool,
+    pub status: Status,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Once we've received a shutdown signal we want the platform to stop sending us traffic,
+// so draining is reported as a failed critical check
+#[async_trait]
+impl HealthCheck for Shutdown {
+    fn name(&self) -> &str {
+        "shutdown"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        if self.is_draining() {
+            Err("server is draining".into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Routes for the liveness and readiness probes.
+pub fn routes(registry: HealthRegistry) -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(healthz))
+        .routes(routes!(readyz))
+        .with_state(registry)
+}
+
+// If we can answer at all, the process is alive
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "health",
+    summary = "Liveness probe",
+    responses((status = 200, description = "The process is alive", body = LivenessReport)),
+)]
+async fn healthz() -> Json<LivenessReport> {
+    Json(LivenessReport {
+        status: Status::Pass,
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    summary = "Readiness probe",
+    description = "Runs every registered health check. Only critical checks can make the server unready.",
+    responses(
+        (status = 200, description = "Ready to serve traffic", body = ReadinessReport),
+        (status = 503, description = "Not ready, for example while draining", body = ReadinessReport),
+    ),
+)]
+async fn readyz(State(registry): State<HealthRegistry>) -> impl IntoResponse {
+    let report = registry.report().await;
+    let status = match report.status {
+        Status::Pass => StatusCode::OK,
+        Status::Fail => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
+}
@@ -0,0 +1,339 @@
+// This starter uses the `axum` crate to create an asyncrohnous web server
+// The async runtime being used, is `tokio`
+// This starter also has logging, powered by `tracing` and `tracing-subscriber`
+//
+// The server lives in this library, and `main.rs` is a thin binary on top of it
+// That way integration tests (and any other binary) run the exact same router as production:
+//   `build_app` builds the router from a config, without binding any sockets
+//   `run` is the whole server: logging, trace export, and serving a listener until a shutdown signal arrives
+//   `run_from_config` does the same, on every listener in the config, which is what `main.rs` uses
+// `App` holds the pieces in between, for callers that want to swap some of them out
+
+use axum::{middleware, Extension, Router};
+use std::{io, sync::Arc};
+use utoipa::OpenApi;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+pub mod access_log;
+pub mod admin;
+pub mod api;
+pub mod auth;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod error;
+pub mod fallback;
+pub mod health;
+pub mod limits;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod openapi;
+pub mod rate_limit;
+pub mod request_id;
+pub mod security_headers;
+pub mod shutdown;
+pub mod state;
+pub mod telemetry;
+pub mod tls;
+pub mod versioning;
+
+use access_log::AccessLog;
+use config::{Config, Environment};
+use fallback::Fallback;
+use health::HealthRegistry;
+use limits::Limits;
+use listener::BoundListener;
+use logging::LogFilter;
+use metrics::Metrics;
+use openapi::ApiDoc;
+use security_headers::SecurityHeaders;
+use shutdown::Shutdown;
+use state::AppState;
+use telemetry::Telemetry;
+
+/// Builds the router we serve in production, with fresh state built from `config`.
+/// The admin routes are mounted when an admin token is configured and a `log_filter` is given,
+/// from `logging::init` or `LogFilter::new`.
+pub fn build_app(config: &Config, log_filter: Option<LogFilter>) -> Result<Router, String> {
+    let mut app = App::new(config)?;
+    app.log_filter = log_filter;
+    app.router()
+}
+
+/// Runs the server on `listener`: sets up logging and trace export,
+/// and serves until SIGTERM or SIGINT, then drains in-flight requests.
+/// `config.listen` is ignored, so another binary (or a test) can bind the socket itself, for example on port 0.
+/// This installs process-wide state (the tracing subscriber, the rustls crypto provider), so it runs once per process.
+/// Failures are logged (or printed, when logging isn't set up yet) before they are returned.
+pub async fn run(config: Config, listener: BoundListener) -> Result<(), String> {
+    start(config, Some(vec![listener])).await
+}
+
+/// Like [`run`], on every address in `config.listen`, which are bound once logging is set up.
+pub async fn run_from_config(config: Config) -> Result<(), String> {
+    start(config, None).await
+}
+
+async fn start(config: Config, listeners: Option<Vec<BoundListener>>) -> Result<(), String> {
+    // Our HTTP clients (and the trace exporter's) use the same TLS implementation as our server, which goes in first
+    tls::install_crypto_provider();
+
+    // When a collector is configured, we also export our spans to it with OpenTelemetry
+    let telemetry = Telemetry::init(&config).inspect_err(|error| eprintln!("{error}"))?;
+
+    // Next, we initialize the tracing subscriber, using the log format and filter from our config
+    // This is what allows us to print things to the console
+    // It hands back a handle, which the admin routes use to change the log filter at runtime
+    let log_filter =
+        logging::init(&config, telemetry.as_ref()).inspect_err(|error| eprintln!("{error}"))?;
+
+    // Secret fields are redacted by their `Debug` implementation, so this is safe to log
+    tracing::info!(?config, "Loaded configuration");
+
+    let result = serve(&config, log_filter, listeners).await;
+
+    // Finally, we make sure spans that haven't been exported yet aren't lost
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown().await;
+    }
+
+    if let Err(error) = &result {
+        tracing::error!("{}", error);
+    }
+    result
+}
+
+async fn serve(
+    config: &Config,
+    log_filter: LogFilter,
+    listeners: Option<Vec<BoundListener>>,
+) -> Result<(), String> {
+    // The app holds our application state, shutdown coordinator and metrics registry
+    let mut app = App::new(config)
+        .map_err(|error| format!("failed to create the application state: {error}"))?;
+    app.log_filter = Some(log_filter);
+
+    // Next, unless we were handed a listener, we need one for every address in our config (by default, dual-stack [::]:PORT)
+    // These can be TCP sockets, or Unix domain sockets when we run behind a local reverse proxy
+    // If any of them fails to bind, we refuse to start, rather than silently serving on fewer addresses
+    // When a certificate is configured, TCP listeners terminate TLS themselves, and pick up renewed certificates from disk
+    let listeners = match listeners {
+        Some(listeners) => {
+            for listener in &listeners {
+                tracing::info!("Listening at {}!", listener.local_addr());
+            }
+            listeners
+        }
+        None => listener::bind_all(config, &app.shutdown)?,
+    };
+
+    // We listen for SIGTERM/SIGINT in the background
+    tokio::spawn(app.shutdown.clone().listen_for_signals());
+
+    // Then, we serve our app until a signal arrives and in-flight requests have drained
+    app.serve(listeners)
+        .await
+        .map_err(|error| format!("server failed: {error}"))
+}
+
+/// Everything the router is built from.
+#[derive(Clone)]
+pub struct App {
+    /// Shared with every handler, see [`AppState`].
+    pub state: AppState,
+    /// The shutdown coordinator, shared between the server and our middleware.
+    pub shutdown: Shutdown,
+    /// The metrics registry, shared by our middleware, the `/metrics` route and any handler that records its own metrics.
+    pub metrics: Metrics,
+    /// The handle the admin routes use to change the log filter.
+    /// It comes from `logging::init`, or `LogFilter::new` for a subscriber of your own; the admin routes are skipped without it.
+    pub log_filter: Option<LogFilter>,
+}
+
+impl App {
+    pub fn new(config: &Config) -> Result<Self, String> {
+        Ok(Self::with_state(AppState::new(config)?))
+    }
+
+    /// An app around an existing state, for example one holding test doubles.
+    pub fn with_state(state: AppState) -> Self {
+        Self {
+            state,
+            shutdown: Shutdown::new(),
+            metrics: Metrics::new(),
+            log_filter: None,
+        }
+    }
+
+    /// Builds the router, with every route and layer.
+    /// Fails if a route can't be built from our config, like an API version whose headers are invalid.
+    pub fn router(&self) -> Result<Router, String> {
+        let config = self.state.config.clone();
+
+        // Components that must be healthy before we accept traffic register a check here
+        // The shutdown coordinator is one of them: once we start draining, we are no longer ready
+        // When a database is configured, we aren't ready until we can reach it
+        let mut health = HealthRegistry::new();
+        health.register(self.shutdown.clone());
+        if let Some(database) = self.state.database.clone() {
+            health.register(database);
+        }
+
+        // First, we create a router, which is a way of routing requests to different handlers
+        // We use utoipa's `OpenApiRouter`, which works like axum's `Router`, but also collects our OpenAPI document
+        let mut app = OpenApiRouter::with_openapi(ApiDoc::openapi())
+            // In order to add a route, we use the `routes` method on the router
+            // The `routes!` macro reads the path and HTTP method from the `#[utoipa::path]` attribute on our handler
+            // In our invocation below, we create a route, that goes to "/"
+            // The code of the root function is below
+            .routes(routes!(root))
+            // Routers can also be merged, here we add our versioned API (see `api.rs`)
+            // It has a more complex route, using a typed JSON response, under `/v1/complex` and `/v2/complex`
+            .merge(api::routes(&config)?)
+            // Our handlers can take the application state (or any part of it) with the `State` extractor
+            // `with_state` provides it to every route added above
+            .with_state(self.state.clone())
+            // Here we add the `/healthz` and `/readyz` probes
+            .merge(health::routes(health))
+            // And `/metrics`, which Prometheus scrapes
+            .merge(metrics::routes(self.metrics.clone()))
+            // And `/openapi.json`, which serves our OpenAPI document (and `/docs`, which renders it)
+            .merge(openapi::routes(config.openapi_docs));
+
+        // The admin routes are only mounted when an admin token is configured
+        if let (Some(token), Some(log_filter)) = (config.admin_token.clone(), &self.log_filter) {
+            app = app.merge(admin::routes(token, log_filter.clone()));
+        }
+
+        // Now that every route is added, we split the OpenAPI document from the axum router
+        // The document routes get it through an extension
+        let (app, document) = app.split_for_parts();
+
+        // Requests that don't match a route get a JSON 404 (or 405 for a known path with the wrong method)
+        // 404s can suggest similar paths; we only point clients to public routes from our document
+        let known_paths = if config.fallback_suggestions {
+            fallback::public_paths(&document)
+        } else {
+            Vec::new()
+        };
+        let app = fallback::install(
+            app.layer(Extension(Arc::new(document))),
+            Fallback {
+                known_paths,
+                metrics: self.metrics.clone(),
+            },
+        );
+
+        // Responses are compressed for clients that accept it, and compressed request bodies are decompressed
+        let app = compression::install(app, &config);
+
+        // Clients over their rate limit get a 429; this goes inside CORS, so browsers can read the 429
+        let app = app.layer(middleware::from_fn_with_state(
+            self.state.rate_limiter.clone(),
+            rate_limit::middleware,
+        ));
+
+        // Bearer tokens are checked before rate limiting, so users can be told apart by their token
+        // Nothing is rejected here; routes that need a user take the `Claims` extractor
+        let app = if self.state.auth.is_configured() {
+            app.layer(middleware::from_fn_with_state(
+                self.state.auth.clone(),
+                auth::middleware,
+            ))
+        } else {
+            app
+        };
+
+        // Requests that would cost us too much are turned away: oversized URIs, headers or bodies,
+        // requests over the in-flight cap, and requests that run past their timeout
+        let app = app.layer(middleware::from_fn_with_state(
+            Arc::new(Limits::new(&config)),
+            limits::middleware,
+        ));
+
+        // Browsers on other origins can call us as allowed by our CORS policy
+        // Preflight requests are answered here, before they can reach the 405 fallback
+        let app = cors::install(app, &config);
+
+        // Every response gets our security headers, including the errors and preflight answers from the layers above
+        // Handlers can take the request's nonce with the `CspNonce` extractor, for pages with inline scripts
+        let app = app.layer(middleware::from_fn_with_state(
+            Arc::new(SecurityHeaders::new(&config)),
+            security_headers::middleware,
+        ));
+
+        // Layers wrap every route above them; this one records in-flight requests
+        // so we can report any that are still running when we shut down
+        Ok(app
+            .layer(middleware::from_fn_with_state(
+                self.shutdown.clone(),
+                shutdown::track_in_flight,
+            ))
+            // Handlers can get the registry with the `Extension<Metrics>` extractor, to register their own metrics
+            .layer(Extension(self.metrics.clone()))
+            .layer(middleware::from_fn_with_state(
+                self.metrics.clone(),
+                metrics::middleware,
+            ))
+            // The access log goes on last, so it wraps everything else and measures the full latency
+            .layer(middleware::from_fn_with_state(
+                Arc::new(AccessLog {
+                    format: config.access_log_format,
+                    exclude: config.access_log_exclude.clone(),
+                    trusted_proxies: config.rate_limit_trusted_proxies.clone().into(),
+                }),
+                access_log::middleware,
+            ))
+            // Internal error details are useful while developing, but can leak information in production
+            // Every error response from the layers above and our handlers follows this app's setting
+            .layer(middleware::from_fn_with_state(
+                config.environment == Environment::Development,
+                error::middleware,
+            ))
+            // Request ids are assigned before anything else, so every other layer can use them
+            .layer(middleware::from_fn(request_id::middleware)))
+    }
+
+    /// Serves the router on every listener until `shutdown_delay` after `shutdown` is triggered.
+    /// In-flight requests then get `shutdown_timeout` to finish; any still running after that are logged and abandoned.
+    pub async fn serve(self, listeners: Vec<BoundListener>) -> io::Result<()> {
+        let app = self.router().map_err(io::Error::other)?;
+        let shutdown = self.shutdown;
+        let config = &self.state.config;
+
+        // We run the server on every listener, using axum's `serve` method (taking both a listener and our axum Router)
+        // Each server stops accepting new connections `shutdown_delay` after shutdown is triggered, and waits for in-flight requests to complete
+        let servers = listeners
+            .into_iter()
+            .map(|listener| listener.serve(app.clone(), shutdown.clone(), config.shutdown_delay));
+
+        // Draining can't take forever, so we race the servers against the drain deadline
+        tokio::select! {
+            // These futures are async, so we need to await them
+            result = futures::future::try_join_all(servers) => {
+                result?;
+                tracing::info!("Server shut down gracefully");
+            }
+            _ = shutdown.deadline(config.shutdown_delay + config.shutdown_timeout) => shutdown.log_abandoned(),
+        }
+        Ok(())
+    }
+}
+
+// This is our route handler, for the route root
+// Make sure the function is `async`
+// We specify our return type, `&'static str`, however a route handler can return anything that implements `IntoResponse`
+// The `#[utoipa::path]` attribute describes the route in our OpenAPI document
+
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "examples",
+    summary = "Say hello",
+    responses((status = 200, description = "A greeting", body = String, content_type = "text/plain")),
+)]
+async fn root() -> &'static str {
+    "Hello, World!"
+}
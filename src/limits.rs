@@ -0,0 +1,242 @@
+// Limits on what a single request can cost us, all set in our config under `limits.*`
+//   URI length      longer URIs are rejected with a 414, before anything else looks at them
+//   header size     requests whose headers add up to more than this get a 431
+//   body size       bodies larger than this (as sent, before any decompression) get a 413
+//   in flight       once this many requests are in flight, new ones are shed with a 503, instead of queueing up
+//                   behind them and making every request slow
+//   timeouts        requests that take longer than `limits.request_timeout` (or their route's timeout) are cut off
+//
+// A timed out request gets a 408 when the client was still sending its body, since then it is the client that is slow,
+// and a 503 otherwise, since then it is us
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::{Frame, SizeHint};
+use http_body_util::Limited;
+use std::{
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+
+use crate::{
+    config::{parse_duration, path_covers, Config},
+    error::Problem,
+};
+
+/// The timeout for the routes under a path prefix, like `/v1=5s`, or `/admin=off` for none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteTimeout {
+    pub prefix: String,
+    pub timeout: Option<Duration>,
+}
+
+impl FromStr for RouteTimeout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, timeout) = s
+            .split_once('=')
+            .filter(|(prefix, _)| prefix.starts_with('/'))
+            .ok_or_else(|| {
+                format!("expected a route timeout like /v1=5s or /admin=off, got {s:?}")
+            })?;
+        let timeout = match timeout.trim() {
+            "off" => None,
+            timeout => Some(parse_duration(timeout)?),
+        };
+        Ok(RouteTimeout {
+            prefix: prefix.trim().to_owned(),
+            timeout,
+        })
+    }
+}
+
+/// The limits from our config, and the requests currently counting against them.
+pub struct Limits {
+    pub max_uri_length: usize,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    pub request_timeout: Duration,
+    pub route_timeouts: Vec<RouteTimeout>,
+    // One permit per request that may be in flight
+    in_flight: Semaphore,
+}
+
+impl Limits {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_uri_length: config.limits_max_uri_length,
+            max_header_size: config.limits_max_header_size,
+            max_body_size: config.limits_max_body_size,
+            request_timeout: config.limits_request_timeout,
+            route_timeouts: config.limits_route_timeouts.clone(),
+            in_flight: Semaphore::new(config.limits_max_in_flight),
+        }
+    }
+
+    // The longest matching prefix wins, like our rate limits
+    fn timeout(&self, path: &str) -> Option<Duration> {
+        self.route_timeouts
+            .iter()
+            .filter(|route| path_covers(&route.prefix, path))
+            .max_by_key(|route| route.prefix.len())
+            .map_or(Some(self.request_timeout), |route| route.timeout)
+    }
+}
+
+/// Middleware that enforces our limits.
+pub async fn middleware(
+    State(limits): State<Arc<Limits>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let uri_length = request.uri().to_string().len();
+    if uri_length > limits.max_uri_length {
+        return Problem::new(
+            StatusCode::URI_TOO_LONG,
+            Some(format!(
+                "The URI is {uri_length} bytes, but at most {} are allowed",
+                limits.max_uri_length
+            )),
+        )
+        .into_response();
+    }
+
+    let header_size = header_size(request.headers());
+    if header_size > limits.max_header_size {
+        return Problem::new(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Some(format!(
+                "The headers are {header_size} bytes, but at most {} are allowed",
+                limits.max_header_size
+            )),
+        )
+        .into_response();
+    }
+
+    // When the client says up front how large the body is, we don't have to wait for it to find out
+    // Otherwise the body stops at the limit, and the extractor reading it rejects the request with a 413
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > limits.max_body_size as u64) {
+        return Problem::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Some(format!(
+                "The body is larger than the limit of {} bytes",
+                limits.max_body_size
+            )),
+        )
+        .into_response();
+    }
+
+    let Ok(_permit) = limits.in_flight.try_acquire() else {
+        tracing::warn!("Too many requests in flight, shedding the request");
+        let mut response = Problem::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            Some("The server is overloaded, try again shortly".into()),
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        return response;
+    };
+
+    let timeout = limits.timeout(request.uri().path());
+    let reading = Arc::new(AtomicU8::new(NOT_STARTED));
+    let max_body_size = limits.max_body_size;
+    let tracked = reading.clone();
+    let request = request.map(|body| {
+        Body::new(TrackedBody {
+            inner: Limited::new(body, max_body_size),
+            reading: tracked,
+        })
+    });
+
+    let Some(timeout) = timeout else {
+        return next.run(request).await;
+    };
+    match tokio::time::timeout(timeout, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) if reading.load(Ordering::Relaxed) == IN_PROGRESS => {
+            tracing::debug!(?timeout, "The client didn't send its request in time");
+            Problem::new(
+                StatusCode::REQUEST_TIMEOUT,
+                Some("The request body wasn't received in time".into()),
+            )
+            .into_response()
+        }
+        Err(_) => {
+            tracing::warn!(?timeout, "A request timed out");
+            Problem::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some(format!(
+                    "The request didn't complete within {}s",
+                    timeout.as_secs_f64()
+                )),
+            )
+            .into_response()
+        }
+    }
+}
+
+// Every header counts its name, its value, and the `: ` and line break around them, as they are sent over HTTP/1.1
+fn header_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+const NOT_STARTED: u8 = 0;
+const IN_PROGRESS: u8 = 1;
+const DONE: u8 = 2;
+
+// A request body that records how far it has been read, so a timeout can tell whose fault it was
+// Its error is the one from `Limited`, so extractors can still tell the body was too large, and answer with a 413
+struct TrackedBody {
+    inner: Limited<Body>,
+    reading: Arc<AtomicU8>,
+}
+
+impl http_body::Body for TrackedBody {
+    type Data = Bytes;
+    type Error = axum::BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        let state = match &poll {
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => DONE,
+            _ if self.inner.is_end_stream() => DONE,
+            _ => IN_PROGRESS,
+        };
+        self.reading.store(state, Ordering::Relaxed);
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,301 @@
+// Listening sockets
+// Whether a socket bound to `[::]` also accepts IPv4 connections normally depends on the kernel's `bindv6only` setting
+// To make that predictable, every listen address says explicitly which kind of socket it wants:
+//   `0.0.0.0:3000`      IPv4 only
+//   `[::]:3000`         IPv6 only (`IPV6_V6ONLY` on)
+//   `dual:[::]:3000`    IPv6 and IPv4-mapped addresses (`IPV6_V6ONLY` off)
+// Behind a local reverse proxy we can also serve over a Unix domain socket:
+//   `unix:/run/app.sock`
+// When TLS is configured, every TCP listener serves HTTPS; Unix domain sockets stay plain HTTP
+
+use axum::{serve::ListenerExt, Router};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    fmt,
+    future::IntoFuture,
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::net::TcpListener;
+
+use crate::{
+    config::Config,
+    shutdown::Shutdown,
+    tls::{Tls, TlsListener},
+};
+
+// How many pending connections the kernel queues for us before refusing new ones
+const BACKLOG: i32 = 1024;
+
+/// An address the server accepts connections on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// A TCP socket; for IPv6 addresses `dual_stack` turns `IPV6_V6ONLY` off.
+    Tcp { addr: SocketAddr, dual_stack: bool },
+    /// A Unix domain socket at the given path.
+    Unix { path: PathBuf },
+}
+
+impl ListenAddr {
+    /// Listens on every IPv6 and IPv4 interface with a single socket.
+    pub fn dual_stack(port: u16) -> Self {
+        ListenAddr::Tcp {
+            addr: SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+            dual_stack: true,
+        }
+    }
+
+    /// Creates and binds the socket.
+    pub fn bind(&self, options: &BindOptions) -> io::Result<BoundListener> {
+        match self {
+            ListenAddr::Tcp { addr, dual_stack } => {
+                let listener = bind_tcp(*addr, *dual_stack)?;
+                match &options.tls {
+                    Some(tls) => tls.listener(listener).map(BoundListener::Tls),
+                    None => Ok(BoundListener::Tcp(listener)),
+                }
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix { path } => {
+                let (listener, file) = unix::bind(path, options.unix_socket_mode)?;
+                Ok(BoundListener::Unix(listener, file))
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// A short description of the kind of socket, for startup logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ListenAddr::Tcp { addr, .. } if addr.is_ipv4() => "IPv4",
+            ListenAddr::Tcp {
+                dual_stack: true, ..
+            } => "dual-stack IPv6/IPv4",
+            ListenAddr::Tcp { .. } => "IPv6 only",
+            ListenAddr::Unix { .. } => "Unix socket",
+        }
+    }
+}
+
+/// Settings that apply to every listener.
+pub struct BindOptions {
+    /// File permissions for Unix domain sockets.
+    pub unix_socket_mode: u32,
+    /// When set, TCP listeners serve HTTPS.
+    pub tls: Option<Tls>,
+}
+
+/// Binds every address in `config.listen`, loading the TLS certificate first when one is configured.
+/// Fails if any address can't be bound, rather than silently serving on fewer addresses.
+/// Renewed certificates are picked up until `shutdown` is triggered.
+pub fn bind_all(config: &Config, shutdown: &Shutdown) -> Result<Vec<BoundListener>, String> {
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = Tls::load(cert, key)
+                .map_err(|error| format!("failed to load TLS certificate: {error}"))?;
+            tls.watch(config.tls_reload_interval, shutdown.clone());
+            Some(tls)
+        }
+        _ => None,
+    };
+    let options = BindOptions {
+        unix_socket_mode: config.unix_socket_mode,
+        tls,
+    };
+
+    config
+        .listen
+        .iter()
+        .map(|addr| {
+            let listener = addr
+                .bind(&options)
+                .map_err(|error| format!("failed to bind {addr}: {error}"))?;
+            tracing::info!("Listening on {} at {}!", addr.kind(), listener.local_addr());
+            Ok(listener)
+        })
+        .collect()
+}
+
+fn bind_tcp(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Lets us rebind straight away after a restart, instead of waiting for old connections to time out
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// A socket that is bound and ready to accept connections.
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, unix::SocketFile),
+}
+
+impl BoundListener {
+    /// The address we actually ended up listening on (for example, with port 0 the kernel picks one).
+    pub fn local_addr(&self) -> String {
+        match self {
+            BoundListener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "<unknown>".into()),
+            BoundListener::Tls(listener) => axum::serve::Listener::local_addr(listener)
+                .map(|addr| format!("https://{addr}"))
+                .unwrap_or_else(|_| "<unknown>".into()),
+            #[cfg(unix)]
+            BoundListener::Unix(_, file) => file.path.display().to_string(),
+        }
+    }
+
+    /// Serves `app` until `delay` after `shutdown` is triggered, then waits for every connection to finish.
+    pub async fn serve(self, app: Router, shutdown: Shutdown, delay: Duration) -> io::Result<()> {
+        // Readiness checks fail from the moment `shutdown` is triggered, and we keep serving until the platform notices
+        let signal = async move {
+            shutdown.triggered().await;
+            tokio::time::sleep(delay).await;
+        };
+        // Over TCP, handlers can see the peer's address with `ConnectInfo<SocketAddr>`
+        match self {
+            BoundListener::Tcp(listener) => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(signal)
+                .into_future()
+                .await
+            }
+            BoundListener::Tls(listener) => {
+                // axum only implements `ConnectInfo` for its own listeners, and ones wrapped with `tap_io`,
+                // so ours is wrapped with one that leaves every connection as it is
+                axum::serve(
+                    listener.tap_io(|_| {}),
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(signal)
+                .into_future()
+                .await
+            }
+            #[cfg(unix)]
+            BoundListener::Unix(listener, _file) => {
+                // `_file` lives until serving stops, then removes the socket file
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(signal)
+                    .into_future()
+                    .await
+            }
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp {
+                addr,
+                dual_stack: true,
+            } => write!(f, "dual:{addr}"),
+            ListenAddr::Tcp { addr, .. } => write!(f, "{addr}"),
+            ListenAddr::Unix { path } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("expected a socket path, like unix:/run/app.sock".into());
+            }
+            return Ok(ListenAddr::Unix { path: path.into() });
+        }
+
+        let (dual_stack, addr) = match s.strip_prefix("dual:") {
+            Some(addr) => (true, addr),
+            None => (false, s),
+        };
+        let addr: SocketAddr = addr
+            .parse()
+            .map_err(|_| "expected an address like 0.0.0.0:3000, [::]:3000 or dual:[::]:3000")?;
+        if dual_stack && addr.is_ipv4() {
+            return Err("dual-stack listeners need an IPv6 address, like dual:[::]:3000".into());
+        }
+        Ok(ListenAddr::Tcp { addr, dual_stack })
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        fs, io,
+        os::unix::{fs::FileTypeExt, fs::PermissionsExt, net::UnixStream},
+        path::{Path, PathBuf},
+    };
+    use tokio::net::UnixListener;
+
+    /// Removes the socket file when dropped, so we don't leave it behind after shutting down.
+    pub struct SocketFile {
+        pub path: PathBuf,
+    }
+
+    impl Drop for SocketFile {
+        fn drop(&mut self) {
+            if let Err(error) = fs::remove_file(&self.path) {
+                tracing::warn!("Failed to remove socket {}: {}", self.path.display(), error);
+            }
+        }
+    }
+
+    pub fn bind(path: &Path, mode: u32) -> io::Result<(UnixListener, SocketFile)> {
+        remove_stale(path)?;
+        let listener = UnixListener::bind(path)?;
+        let file = SocketFile {
+            path: path.to_owned(),
+        };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        Ok((listener, file))
+    }
+
+    // A socket file left behind by a crashed process would make binding fail, so we clean it up
+    // We only remove it if it really is a socket, and nobody is accepting connections on it anymore
+    fn remove_stale(path: &Path) -> io::Result<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another process is listening on {}", path.display()),
+            )),
+            Err(_) => {
+                tracing::info!("Removing stale socket {}", path.display());
+                fs::remove_file(path)
+            }
+        }
+    }
+}
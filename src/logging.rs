@@ -0,0 +1,355 @@
+// Logging setup for the tracing subscriber
+// Output can be human readable (`full`, `compact` or `pretty`) or one JSON object per line (`json`)
+// Filtering uses `RUST_LOG`-style directives, like `info,rust_starter=debug`
+// Timestamps are always UTC in RFC 3339 format, and every event carries our static fields (service name, deployment id, ...)
+// The filter can be swapped at runtime through a `LogFilter` handle, so we can turn on debug logs without redeploying
+
+use std::{
+    fmt,
+    io::IsTerminal,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::{Format, JsonFields, PrettyFields, Writer},
+        time::SystemTime,
+        FmtContext, FormatEvent, FormatFields, MakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{config::Config, telemetry::Telemetry};
+
+/// How log events are written to stdout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected one of full, compact, pretty or json".into()),
+        }
+    }
+}
+
+/// A `key=value` pair added to every log event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticField {
+    pub key: String,
+    pub value: String,
+}
+
+impl StaticField {
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+}
+
+// Keys every event already has: the JSON formatter's own, and our other static fields
+const RESERVED_KEYS: &[&str] = &[
+    "timestamp",
+    "level",
+    "target",
+    "fields",
+    "span",
+    "spans",
+    "filename",
+    "line_number",
+    "threadId",
+    "threadName",
+    "service",
+    "deployment_id",
+];
+
+impl FromStr for StaticField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((key, value)) = s.split_once('=') else {
+            return Err("expected key=value".into());
+        };
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        {
+            return Err(format!(
+                "{key:?} isn't a valid key, use letters, digits, `_`, `-` and `.`"
+            ));
+        }
+        if RESERVED_KEYS.contains(&key) {
+            return Err(format!("{key} is already on every event"));
+        }
+        Ok(StaticField::new(key, value.trim()))
+    }
+}
+
+/// Installs the global tracing subscriber, returning a handle to change its filter later.
+/// With `telemetry`, spans are exported to OpenTelemetry as well.
+/// Fails if `config.log_filter` isn't valid, which can only happen when the config wasn't loaded by `Config`.
+pub fn init(config: &Config, telemetry: Option<&Telemetry>) -> Result<LogFilter, String> {
+    let (log_filter, filter) = LogFilter::new(&config.log_filter)?;
+
+    // Colours only make sense when a person is looking at a terminal
+    let ansi = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer(config, std::io::stdout, ansi))
+        .with(telemetry.map(Telemetry::layer))
+        .init();
+
+    Ok(log_filter)
+}
+
+/// The layer that writes log events to `writer`, in `config.log_format` and with our static fields.
+/// `init` writes to stdout; anything else, like a test, can write wherever it can read the events back.
+pub fn layer<S, W>(config: &Config, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let mut fields = vec![StaticField::new("service", &config.log_service)];
+    if let Some(deployment_id) = &config.log_deployment_id {
+        fields.push(StaticField::new("deployment_id", deployment_id));
+    }
+    fields.extend(config.log_fields.iter().cloned());
+
+    // `SystemTime` prints UTC timestamps in RFC 3339 format, like `2024-01-01T12:00:00.000000Z`
+    let text = Format::default().with_timer(SystemTime).with_ansi(ansi);
+
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match config.log_format {
+        LogFormat::Full => layer
+            .event_format(StaticFields::text(text, &fields))
+            .boxed(),
+        LogFormat::Compact => layer
+            .event_format(StaticFields::text(text.compact(), &fields))
+            .boxed(),
+        LogFormat::Pretty => layer
+            .fmt_fields(PrettyFields::new())
+            .event_format(StaticFields::text(text.pretty(), &fields))
+            .boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(JsonFields::new())
+            .event_format(StaticFields::json(
+                // Include the fields of the current span, and of every span it is nested in
+                Format::default()
+                    .json()
+                    .with_timer(SystemTime)
+                    .with_current_span(true)
+                    .with_span_list(true),
+                &fields,
+            ))
+            .boxed(),
+    }
+}
+
+/// A handle to read and replace the active log filter.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    state: Arc<Mutex<FilterState>>,
+}
+
+struct FilterState {
+    directives: String,
+    // Bumped on every change, so a pending revert can tell it has been overtaken
+    generation: u64,
+}
+
+impl LogFilter {
+    /// A handle for a filter starting out as `directives`, and the layer it controls.
+    ///
+    /// `init` puts the layer in the global subscriber; anything else that builds its own subscriber
+    /// (or none at all, like our tests) can still hand the handle to the admin routes.
+    /// Fails if `directives` aren't valid.
+    pub fn new(directives: &str) -> Result<(Self, reload::Layer<EnvFilter, Registry>), String> {
+        // Wrapping the filter in a reload layer lets us replace it while the subscriber is running
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|error| format!("invalid log filter {directives:?}: {error}"))?;
+        let (layer, handle) = reload::Layer::new(filter);
+        let log_filter = Self {
+            handle,
+            state: Arc::new(Mutex::new(FilterState {
+                directives: directives.to_owned(),
+                generation: 0,
+            })),
+        };
+        Ok((log_filter, layer))
+    }
+
+    /// The directives currently in effect.
+    pub fn directives(&self) -> String {
+        self.state.lock().unwrap().directives.clone()
+    }
+
+    /// Replaces the active filter with `directives`.
+    ///
+    /// With `revert_after`, the previous directives are restored once that much time has passed,
+    /// unless the filter has been changed again in the meantime.
+    pub fn set(&self, directives: &str, revert_after: Option<Duration>) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|error| error.to_string())?;
+
+        let mut state = self.state.lock().unwrap();
+        let previous = self.apply(&mut state, filter, directives)?;
+
+        if let Some(revert_after) = revert_after {
+            let this = self.clone();
+            let generation = state.generation;
+            tokio::spawn(async move {
+                tokio::time::sleep(revert_after).await;
+                this.revert(generation, &previous);
+            });
+        }
+        Ok(())
+    }
+
+    fn revert(&self, generation: u64, directives: &str) {
+        // We hold the lock from the check until the filter is replaced, so a change made in between can't be undone
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        // These directives were in effect before, so they are known to be valid
+        let result = EnvFilter::try_new(directives)
+            .map_err(|error| error.to_string())
+            .and_then(|filter| self.apply(&mut state, filter, directives));
+        if let Err(error) = result {
+            tracing::error!("Failed to revert log filter: {}", error);
+        }
+    }
+
+    // Swaps in `filter` while the caller holds the lock, returning the directives it replaced
+    fn apply(
+        &self,
+        state: &mut FilterState,
+        filter: EnvFilter,
+        directives: &str,
+    ) -> Result<String, String> {
+        self.handle
+            .reload(filter)
+            .map_err(|error| error.to_string())?;
+        let previous = std::mem::replace(&mut state.directives, directives.to_owned());
+        state.generation += 1;
+        tracing::info!(%previous, current = directives, "Changed log filter");
+        Ok(previous)
+    }
+}
+
+// Wraps an event formatter, adding our static fields to every event it writes
+struct StaticFields<F> {
+    inner: F,
+    // The fields, already rendered in the output format
+    rendered: String,
+    json: bool,
+}
+
+impl<F> StaticFields<F> {
+    // Text events get the fields appended, like `... service=rust-starter note="two words"`
+    // Values that would break up the `key=value` pairs are quoted, like the formatter quotes string fields
+    fn text(inner: F, fields: &[StaticField]) -> Self {
+        let rendered = fields
+            .iter()
+            .map(|field| {
+                let plain = !field.value.is_empty()
+                    && !field.value.contains(|c: char| {
+                        c.is_whitespace() || c.is_control() || c == '=' || c == '"'
+                    });
+                if plain {
+                    format!(" {}={}", field.key, field.value)
+                } else {
+                    format!(" {}={:?}", field.key, field.value)
+                }
+            })
+            .collect();
+        Self {
+            inner,
+            rendered,
+            json: false,
+        }
+    }
+
+    // JSON events get the fields as extra top-level keys
+    fn json(inner: F, fields: &[StaticField]) -> Self {
+        let rendered = fields
+            .iter()
+            .map(|field| {
+                format!(
+                    "{}:{},",
+                    serde_json::Value::from(field.key.as_str()),
+                    serde_json::Value::from(field.value.as_str())
+                )
+            })
+            .collect();
+        Self {
+            inner,
+            rendered,
+            json: true,
+        }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for StaticFields<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if self.rendered.is_empty() {
+            return self.inner.format_event(ctx, writer, event);
+        }
+
+        // Formatters end every event with a newline, so we format into a buffer and add our fields in the right place
+        let mut buffer = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut buffer), event)?;
+
+        if self.json {
+            match buffer.strip_prefix('{') {
+                Some(rest) => write!(writer, "{{{}{}", self.rendered, rest),
+                None => writer.write_str(&buffer),
+            }
+        } else {
+            let line = buffer.trim_end_matches('\n');
+            writeln!(writer, "{}{}", line, self.rendered)
+        }
+    }
+}
+
+/// Checks that `filter` is a valid set of `RUST_LOG`-style directives.
+pub fn validate_filter(filter: &str) -> Result<String, String> {
+    EnvFilter::try_new(filter)
+        .map(|_| filter.to_owned())
+        .map_err(|error| error.to_string())
+}
@@ -0,0 +1,287 @@
+// Prometheus metrics
+// `/metrics` serves everything in our registry in the Prometheus text exposition format:
+//   RED metrics (rate, errors, duration) for every request, labelled by method, matched route and status class
+//   process metrics (CPU, memory, file descriptors), on Linux
+//   Tokio runtime metrics (workers, alive tasks, queue depth)
+// Handlers can register their own counters and gauges with `Metrics::counter` and `Metrics::gauge`
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::{sync::Arc, time::Instant};
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+// Route label for requests that didn't match any route, so arbitrary paths can't explode our label values
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// The metrics registry, and the metrics we record for every request.
+///
+/// Cloning is cheap; every clone shares the same registry.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    registry: Registry,
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    duration: HistogramVec,
+    unmatched: IntCounterVec,
+    runtime: RuntimeMetrics,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let labels = &["method", "route", "status_class"];
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Total number of HTTP requests"),
+            labels,
+        )
+        .unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "http_request_errors_total",
+                "Total number of HTTP requests that failed with a server error (5xx)",
+            ),
+            labels,
+        )
+        .unwrap();
+        let duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to produce a response, in seconds",
+            ),
+            labels,
+        )
+        .unwrap();
+        let unmatched = IntCounterVec::new(
+            Opts::new(
+                "http_unmatched_requests_total",
+                "Total number of HTTP requests that didn't match a route (not_found) or its methods (method_not_allowed)",
+            ),
+            &["method", "reason"],
+        )
+        .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        registry.register(Box::new(unmatched.clone())).unwrap();
+
+        #[cfg(target_os = "linux")]
+        registry
+            .register(Box::new(
+                prometheus::process_collector::ProcessCollector::for_self(),
+            ))
+            .unwrap();
+
+        let runtime = RuntimeMetrics::new(&registry);
+
+        Self {
+            inner: Arc::new(Inner {
+                registry,
+                requests,
+                errors,
+                duration,
+                unmatched,
+                runtime,
+            }),
+        }
+    }
+
+    /// The underlying registry, for registering any kind of Prometheus collector.
+    pub fn registry(&self) -> &Registry {
+        &self.inner.registry
+    }
+
+    /// Registers a new counter. Fails if a metric with the same name already exists.
+    pub fn counter(&self, name: &str, help: &str) -> prometheus::Result<IntCounter> {
+        let counter = IntCounter::new(name, help)?;
+        self.registry().register(Box::new(counter.clone()))?;
+        Ok(counter)
+    }
+
+    /// Registers a new gauge. Fails if a metric with the same name already exists.
+    pub fn gauge(&self, name: &str, help: &str) -> prometheus::Result<IntGauge> {
+        let gauge = IntGauge::new(name, help)?;
+        self.registry().register(Box::new(gauge.clone()))?;
+        Ok(gauge)
+    }
+
+    fn record(&self, method: &Method, route: &str, status: u16, started: Instant) {
+        let status_class = match status {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        };
+        let labels = &[method_label(method), route, status_class];
+
+        self.inner.requests.with_label_values(labels).inc();
+        if status >= 500 {
+            self.inner.errors.with_label_values(labels).inc();
+        }
+        self.inner
+            .duration
+            .with_label_values(labels)
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Counts a request that no route could handle, for `reason` `not_found` or `method_not_allowed`.
+    pub fn record_unmatched(&self, method: &Method, reason: &str) {
+        self.inner
+            .unmatched
+            .with_label_values(&[method_label(method), reason])
+            .inc();
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        self.inner.runtime.update();
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.inner.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+// Clients can send any method, so anything non-standard is grouped together to keep the label bounded
+fn method_label(method: &Method) -> &str {
+    match *method {
+        Method::GET
+        | Method::HEAD
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::CONNECT
+        | Method::OPTIONS
+        | Method::TRACE
+        | Method::PATCH => method.as_str(),
+        _ => "other",
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Tokio runtime gauges, refreshed every time the metrics are scraped
+struct RuntimeMetrics {
+    workers: IntGauge,
+    alive_tasks: IntGauge,
+    global_queue_depth: IntGauge,
+    #[cfg(target_has_atomic = "64")]
+    busy_seconds: prometheus::Gauge,
+}
+
+impl RuntimeMetrics {
+    fn new(registry: &Registry) -> Self {
+        let gauge = |name: &str, help: &str| {
+            let gauge = IntGauge::new(name, help).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+        };
+
+        Self {
+            workers: gauge(
+                "tokio_workers",
+                "Number of worker threads used by the Tokio runtime",
+            ),
+            alive_tasks: gauge(
+                "tokio_alive_tasks",
+                "Number of tasks currently alive in the Tokio runtime",
+            ),
+            global_queue_depth: gauge(
+                "tokio_global_queue_depth",
+                "Number of tasks waiting in the Tokio runtime's global queue",
+            ),
+            #[cfg(target_has_atomic = "64")]
+            busy_seconds: {
+                let gauge = prometheus::Gauge::new(
+                    "tokio_workers_busy_seconds_total",
+                    "Total time Tokio worker threads have spent busy, in seconds",
+                )
+                .unwrap();
+                registry.register(Box::new(gauge.clone())).unwrap();
+                gauge
+            },
+        }
+    }
+
+    fn update(&self) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let metrics = handle.metrics();
+        self.workers.set(metrics.num_workers() as i64);
+        self.alive_tasks.set(metrics.num_alive_tasks() as i64);
+        self.global_queue_depth
+            .set(metrics.global_queue_depth() as i64);
+        #[cfg(target_has_atomic = "64")]
+        self.busy_seconds.set(
+            (0..metrics.num_workers())
+                .map(|worker| metrics.worker_total_busy_duration(worker).as_secs_f64())
+                .sum(),
+        );
+    }
+}
+
+/// Middleware that records the RED metrics for every request.
+pub async fn middleware(State(metrics): State<Metrics>, request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned());
+
+    let response = next.run(request).await;
+
+    metrics.record(
+        &method,
+        route.as_deref().unwrap_or(UNMATCHED_ROUTE),
+        response.status().as_u16(),
+        started,
+    );
+    response
+}
+
+/// The `/metrics` route.
+pub fn routes(metrics: Metrics) -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(render))
+        .with_state(metrics)
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "observability",
+    summary = "Prometheus metrics",
+    responses((
+        status = 200,
+        description = "Every metric, in the Prometheus text exposition format",
+        body = String,
+        content_type = "text/plain; version=0.0.4",
+    )),
+)]
+async fn render(State(metrics): State<Metrics>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
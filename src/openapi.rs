@@ -0,0 +1,173 @@
+// The OpenAPI 3.1 document for our API
+// Handlers describe themselves with `#[utoipa::path]`, and request/response types derive `ToSchema`
+// Routes are registered through `OpenApiRouter`, which collects the document as the router is built,
+// so every route we serve is in the document, and nothing else is
+// `/openapi.json` serves the document, and `/docs` renders it with Redoc (unless `openapi.docs` is off)
+// The Redoc page and its script are embedded in our binary, so the docs never run code from a third party
+// The script is Redoc 2.0.0-rc.72, from `assets/redoc`; to upgrade, replace the bundle there
+// Our default Content-Security-Policy doesn't let pages load anything, so the page sends its own,
+// which allows Redoc's script and fonts, and its inline scripts through the request's nonce
+
+use axum::{
+    http::header,
+    response::{Html, IntoResponse},
+    Extension, Json,
+};
+use std::sync::Arc;
+use utoipa::{
+    openapi::{
+        security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as Document,
+    },
+    Modify, OpenApi,
+};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_redoc::Redoc;
+
+use crate::{
+    error::Problem,
+    security_headers::{ContentSecurityPolicy, CspNonce, Source},
+};
+
+// Redoc's standalone bundle, which `/docs` loads from us
+const REDOC_JS: &str = include_str!("../assets/redoc/redoc.standalone.js");
+
+// Redoc's own page, with a `$nonce` on its scripts
+// Its Google Fonts stylesheet is left out, so the docs don't load anything from third parties: they use the system fonts
+// `$spec` and `$config` are filled in by `utoipa_redoc`
+const REDOC_HTML: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Redoc</title>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1" />
+    <style>
+      body {
+        margin: 0;
+        padding: 0;
+      }
+    </style>
+  </head>
+
+  <body>
+    <div id="redoc-container"></div>
+    <script nonce="$nonce" src="/docs/redoc.standalone.js"></script>
+    <script nonce="$nonce">
+      Redoc.init(
+        $spec,
+        $config,
+        document.getElementById("redoc-container")
+      );
+    </script>
+  </body>
+</html>
+"#;
+
+/// The parts of the document that don't come from a route, like its title.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rust-starter", description = "A starter API built with axum"),
+    components(schemas(Problem)),
+    modifiers(&Extras),
+)]
+pub struct ApiDoc;
+
+// The parts the derive macro can't express
+struct Extras;
+
+impl Modify for Extras {
+    fn modify(&self, openapi: &mut Document) {
+        // The license is taken from our Cargo.toml, which doesn't have one
+        openapi.info.license = None;
+
+        // The bearer token that protects the admin routes
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        // The bearer JWTs that `auth::Claims` checks
+        components.add_security_scheme(
+            "jwt",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// Routes that serve the document, and the Redoc page when `docs` is true.
+/// The document itself is only known once every route is added, so it is provided afterwards,
+/// with `Extension(Arc<Document>)`.
+pub fn routes(docs: bool) -> OpenApiRouter {
+    let router = OpenApiRouter::new().routes(routes!(openapi_json));
+    if docs {
+        router.routes(routes!(redoc)).routes(routes!(redoc_js))
+    } else {
+        router
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "documentation",
+    summary = "This OpenAPI document",
+    responses((status = 200, description = "The OpenAPI 3.1 document for this API", body = Object)),
+)]
+async fn openapi_json(Extension(document): Extension<Arc<Document>>) -> Json<Document> {
+    Json(document.as_ref().clone())
+}
+
+#[utoipa::path(
+    get,
+    path = "/docs",
+    tag = "documentation",
+    summary = "API documentation",
+    responses((status = 200, description = "The OpenAPI document rendered by Redoc", body = String, content_type = "text/html")),
+)]
+async fn redoc(
+    Extension(document): Extension<Arc<Document>>,
+    nonce: CspNonce,
+) -> impl IntoResponse {
+    let html = Redoc::new(document.as_ref().clone())
+        .custom_html(REDOC_HTML.replace("$nonce", nonce.as_str()))
+        .to_html();
+    (
+        [(
+            header::CONTENT_SECURITY_POLICY,
+            docs_policy().render(&nonce),
+        )],
+        Html(html),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/docs/redoc.standalone.js",
+    tag = "documentation",
+    summary = "The Redoc script for the API documentation",
+    responses((status = 200, description = "Redoc's standalone bundle", body = String, content_type = "text/javascript")),
+)]
+async fn redoc_js() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/javascript; charset=utf-8"),
+            // The bundle only changes with our binary
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        REDOC_JS,
+    )
+}
+
+// Redoc styles the page inline, and runs web workers it starts from blobs
+fn docs_policy() -> ContentSecurityPolicy {
+    ContentSecurityPolicy::strict()
+        .script_src([Source::Nonce, Source::StrictDynamic])
+        .style_src([Source::UnsafeInline])
+        .img_src([Source::SelfOrigin, Source::Scheme("data:".into())])
+        .worker_src([Source::Scheme("blob:".into())])
+}
@@ -0,0 +1,492 @@
+// Rate limiting, with the generic cell rate algorithm (GCRA)
+// GCRA behaves like a token bucket that refills continuously: a client with a quota of 100 requests a minute
+// can make 100 requests at once, and then one more every 600ms, as the bucket refills
+// Instead of a token count, it only stores one timestamp per client, the "theoretical arrival time" (TAT):
+// when the client's bucket will be full again, if it stops making requests
+//
+// Every request is counted against a quota, from the longest matching path prefix in `rate_limit.routes`,
+// or `rate_limit.default` otherwise, per client (by IP address, API key or user, see `rate_limit.key`)
+// A client's IP address is the peer that connected to us, unless that peer is one of `rate_limit.trusted_proxies`:
+// then it's the last address in `X-Forwarded-For` that isn't one of our proxies (anything before it could be made up)
+// Over quota, the request is rejected with a 429 and a `Retry-After` header
+// Every limited response also says where the client stands, with the `RateLimit-*` headers:
+//   RateLimit-Limit      requests allowed per period
+//   RateLimit-Remaining  requests the client can make right now
+//   RateLimit-Reset      seconds until the client's quota is fully available again
+//   RateLimit-Policy     the quota, like `100;w=60` (100 requests per 60 seconds)
+//
+// TATs are in microseconds, so rounding the emission interval down to a whole one barely changes the rate,
+// and a request is allowed while the TAT stays within `limit` emission intervals of now (rather than the period,
+// which the rounding would leave room for an extra request in)
+// The TATs are kept in a `RateLimitStore`: in memory for a single replica,
+// or in Redis so every replica shares the same limits (see `rate_limit/redis.rs`)
+// When the store fails, we let requests through, rather than taking the whole API down with it
+
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{path_covers, Config},
+    error::Problem,
+};
+
+mod redis;
+
+pub use self::redis::RedisStore;
+
+static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+static RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+/// How many requests a client may make per period, like `100/1m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    fn period_us(&self) -> u64 {
+        (self.period.as_micros() as u64).max(1)
+    }
+
+    // How often the bucket gains a request
+    fn emission_us(&self) -> u64 {
+        (self.period_us() / u64::from(self.limit)).max(1)
+    }
+
+    // How far ahead of now a full bucket's worth of requests puts the TAT
+    fn burst_us(&self) -> u64 {
+        self.emission_us() * u64::from(self.limit)
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected a quota like 100/1m or 10/s, got {s:?}");
+        let (limit, period) = s.split_once('/').ok_or_else(error)?;
+        let limit: u32 = limit.trim().parse().map_err(|_| error())?;
+        // `10/s` is short for `10/1s`
+        let period = period.trim();
+        let period = if period.starts_with(|c: char| c.is_ascii_digit()) {
+            crate::config::parse_duration(period)?
+        } else {
+            crate::config::parse_duration(&format!("1{period}"))?
+        };
+        if limit == 0 || period.is_zero() {
+            return Err(format!(
+                "{s:?}: the limit and period must be more than zero"
+            ));
+        }
+        // The emission interval is rounded down to whole microseconds, which only stays negligible above a millisecond
+        if period.as_millis() < u128::from(limit) {
+            return Err(format!(
+                "{s:?}: at most one request per millisecond is supported"
+            ));
+        }
+        Ok(Quota { limit, period })
+    }
+}
+
+/// The quota for the routes under a path prefix, like `/v1=100/1m`, or `/healthz=off` for no limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteQuota {
+    pub prefix: String,
+    pub quota: Option<Quota>,
+}
+
+impl FromStr for RouteQuota {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, quota) = s
+            .split_once('=')
+            .filter(|(prefix, _)| prefix.starts_with('/'))
+            .ok_or_else(|| {
+                format!("expected a route quota like /v1=100/1m or /healthz=off, got {s:?}")
+            })?;
+        Ok(RouteQuota {
+            prefix: prefix.trim().to_owned(),
+            quota: parse_quota(quota.trim())?,
+        })
+    }
+}
+
+/// Parses a quota, or `off` for no limit.
+pub fn parse_quota(value: &str) -> Result<Option<Quota>, String> {
+    match value {
+        "off" => Ok(None),
+        quota => quota.parse().map(Some),
+    }
+}
+
+/// What clients are told apart by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// The client's IP address, see `rate_limit.trusted_proxies`.
+    Ip,
+    /// The API key header (`rate_limit.api_key_header`), or the IP address for requests without one.
+    ApiKey,
+    /// The authenticated [`UserId`], or the IP address for anonymous requests.
+    User,
+}
+
+impl FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(RateLimitKey::Ip),
+            "api_key" => Ok(RateLimitKey::ApiKey),
+            "user" => Ok(RateLimitKey::User),
+            _ => Err(format!("expected ip, api_key or user, got {s:?}")),
+        }
+    }
+}
+
+/// A proxy, or a range of them, whose `X-Forwarded-For` hops we believe, like `10.0.0.0/8` or `192.0.2.1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy(pub IpNet);
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<IpNet>()
+            .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+            .map(TrustedProxy)
+            .map_err(|_| format!("expected an address or a CIDR range like 10.0.0.0/8, got {s:?}"))
+    }
+}
+
+/// Where rate limit state is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitBackend {
+    Memory,
+    Redis,
+}
+
+impl FromStr for RateLimitBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(RateLimitBackend::Memory),
+            "redis" => Ok(RateLimitBackend::Redis),
+            _ => Err(format!("expected memory or redis, got {s:?}")),
+        }
+    }
+}
+
+/// The authenticated user a request is made by.
+/// Authentication middleware inserts it into the request extensions, for `rate_limit.key = user`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserId(pub String);
+
+/// Whether a request was allowed, and how far ahead of now the client's TAT is afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    pub allowed: bool,
+    /// Microseconds until the client's bucket is full again.
+    pub backlog_us: u64,
+}
+
+/// Applies GCRA to a client's stored TAT, at `now` (both in microseconds).
+/// Returns the outcome, and the TAT to store when the request is allowed.
+/// Stores that can't run this code (like Redis, which runs a Lua version of it) must do the same.
+pub fn gcra(tat: Option<u64>, now: u64, quota: Quota) -> (Outcome, Option<u64>) {
+    let tat = tat.unwrap_or(now).max(now);
+    let new_tat = tat + quota.emission_us();
+    if new_tat - now > quota.burst_us() {
+        let outcome = Outcome {
+            allowed: false,
+            backlog_us: tat - now,
+        };
+        (outcome, None)
+    } else {
+        let outcome = Outcome {
+            allowed: true,
+            backlog_us: new_tat - now,
+        };
+        (outcome, Some(new_tat))
+    }
+}
+
+/// Where clients' TATs are kept.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Counts a request by the client with this `key` against `quota`.
+    /// This must be atomic: concurrent requests by the same client must see each other's updates.
+    async fn acquire(
+        &self,
+        key: &str,
+        quota: Quota,
+    ) -> Result<Outcome, Box<dyn Error + Send + Sync>>;
+}
+
+/// A store for a single replica, which keeps TATs in memory.
+pub struct MemoryStore {
+    started: Instant,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    tats: HashMap<String, u64>,
+    // Expired TATs are swept once there are this many, so clients that went away don't pile up
+    sweep_at: usize,
+}
+
+const MIN_SWEEP: usize = 1024;
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            buckets: Mutex::new(Buckets {
+                tats: HashMap::new(),
+                sweep_at: MIN_SWEEP,
+            }),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn acquire(
+        &self,
+        key: &str,
+        quota: Quota,
+    ) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+        let now = self.started.elapsed().as_micros() as u64;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.tats.len() >= buckets.sweep_at {
+            // A TAT in the past is the same as no TAT at all
+            buckets.tats.retain(|_, tat| *tat > now);
+            buckets.sweep_at = (buckets.tats.len() * 2).max(MIN_SWEEP);
+        }
+
+        let (outcome, new_tat) = gcra(buckets.tats.get(key).copied(), now, quota);
+        if let Some(new_tat) = new_tat {
+            buckets.tats.insert(key.to_owned(), new_tat);
+        }
+        Ok(outcome)
+    }
+}
+
+/// The rate limiter: our quotas, and the store that counts requests against them.
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub store: Arc<dyn RateLimitStore>,
+    /// The quota for routes without one of their own, or `None` for no limit.
+    pub default: Option<Quota>,
+    pub routes: Arc<[RouteQuota]>,
+    pub key: RateLimitKey,
+    pub api_key_header: HeaderName,
+    pub trusted_proxies: Arc<[TrustedProxy]>,
+}
+
+impl RateLimiter {
+    /// A rate limiter with the quotas and store from our config.
+    /// The Redis store connects lazily, so this doesn't fail when Redis is down.
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let store: Arc<dyn RateLimitStore> = match config.rate_limit_backend {
+            RateLimitBackend::Memory => Arc::new(MemoryStore::new()),
+            RateLimitBackend::Redis => {
+                let url = config
+                    .rate_limit_redis_url
+                    .as_ref()
+                    .ok_or("rate_limit.redis_url is required for the redis backend")?;
+                Arc::new(RedisStore::new(url.expose())?)
+            }
+        };
+        Ok(Self {
+            store,
+            default: config.rate_limit_default,
+            routes: config.rate_limit_routes.clone().into(),
+            key: config.rate_limit_key,
+            api_key_header: config.rate_limit_api_key_header.clone(),
+            trusted_proxies: config.rate_limit_trusted_proxies.clone().into(),
+        })
+    }
+
+    // The quota for `path`, and the name of the bucket it counts against
+    fn quota(&self, path: &str) -> Option<(Quota, &str)> {
+        // The longest matching prefix wins, so `/v1/complex` can be given a quota of its own within `/v1`
+        match self
+            .routes
+            .iter()
+            .filter(|route| path_covers(&route.prefix, path))
+            .max_by_key(|route| route.prefix.len())
+        {
+            Some(route) => route.quota.map(|quota| (quota, route.prefix.as_str())),
+            None => self.default.map(|quota| (quota, "*")),
+        }
+    }
+
+    fn client(&self, request: &Request) -> String {
+        let headers = request.headers();
+        match self.key {
+            RateLimitKey::ApiKey => {
+                if let Some(api_key) = headers.get(&self.api_key_header) {
+                    // API keys are secrets, so they are hashed before they end up in a store
+                    // The hash must be the same in every replica and every release, or a key's count would reset
+                    let mut key = String::from("key:");
+                    for byte in Sha256::digest(api_key.as_bytes()) {
+                        write!(key, "{byte:02x}").unwrap();
+                    }
+                    return key;
+                }
+            }
+            RateLimitKey::User => {
+                if let Some(UserId(user)) = request.extensions().get::<UserId>() {
+                    return format!("user:{user}");
+                }
+            }
+            RateLimitKey::Ip => {}
+        }
+        format!("ip:{}", client_ip(request, &self.trusted_proxies))
+    }
+}
+
+// Every proxy appends the address that connected to it to `X-Forwarded-For`,
+// so we walk it back from the peer, through our own proxies, to the first address that isn't one of them
+// The access log names the client the same way
+pub(crate) fn client_ip(request: &Request, trusted_proxies: &[TrustedProxy]) -> String {
+    let trusted = |addr: &IpAddr| {
+        trusted_proxies
+            .iter()
+            .any(|TrustedProxy(proxies)| proxies.contains(addr))
+    };
+    // Requests over a Unix domain socket have no peer address: they come from the proxy in front of us
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let mut client = match peer {
+        Some(peer) if !trusted(&peer) => return peer.to_string(),
+        Some(peer) => peer.to_string(),
+        None => "-".to_owned(),
+    };
+
+    let hops: Vec<&str> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .collect();
+    for hop in hops.into_iter().rev() {
+        // Some proxies add the port too
+        let Some(addr) = hop
+            .parse::<IpAddr>()
+            .ok()
+            .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        else {
+            // Not an address, so not one of our proxies either
+            return hop.to_owned();
+        };
+        client = addr.to_string();
+        if !trusted(&addr) {
+            break;
+        }
+    }
+    client
+}
+
+/// Middleware that counts every request against its quota, and rejects those over it.
+pub async fn middleware(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some((quota, bucket)) = limiter.quota(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    let key = format!("rate_limit:{bucket}:{}", limiter.client(&request));
+
+    let outcome = match limiter.store.acquire(&key, quota).await {
+        Ok(outcome) => outcome,
+        Err(error) => {
+            tracing::warn!(%error, "Rate limit store failed, letting the request through");
+            return next.run(request).await;
+        }
+    };
+
+    let mut response = if outcome.allowed {
+        next.run(request).await
+    } else {
+        // The client can retry once a request's worth of the bucket has refilled
+        let retry_after = seconds(outcome.backlog_us + quota.emission_us() - quota.burst_us());
+        tracing::debug!(key, retry_after, "Rate limited a request");
+        let mut response = Problem::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(format!(
+                "Too many requests, try again in {retry_after} seconds"
+            )),
+        )
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    };
+
+    // This request has taken one out of the bucket, so at most `limit - 1` are left
+    let remaining = if outcome.allowed {
+        (quota.burst_us().saturating_sub(outcome.backlog_us) / quota.emission_us())
+            .min(u64::from(quota.limit) - 1)
+    } else {
+        0
+    };
+    insert_headers(
+        response.headers_mut(),
+        quota,
+        remaining,
+        seconds(outcome.backlog_us),
+    );
+    response
+}
+
+fn insert_headers(headers: &mut HeaderMap, quota: Quota, remaining: u64, reset: u64) {
+    headers.insert(RATELIMIT_LIMIT.clone(), HeaderValue::from(quota.limit));
+    headers.insert(RATELIMIT_REMAINING.clone(), HeaderValue::from(remaining));
+    headers.insert(RATELIMIT_RESET.clone(), HeaderValue::from(reset));
+    headers.insert(
+        RATELIMIT_POLICY.clone(),
+        HeaderValue::from_str(&format!("{};w={}", quota.limit, seconds(quota.period_us())))
+            .unwrap(),
+    );
+}
+
+// Whole seconds, rounded up, so clients never retry too early
+fn seconds(us: u64) -> u64 {
+    us.div_ceil(1_000_000)
+}
@@ -0,0 +1,92 @@
+// A rate limit store in Redis, so every replica of our server shares the same limits
+// GCRA runs inside Redis as a Lua script, which Redis runs atomically, so concurrent requests can't race each other
+// The script uses Redis' clock rather than ours, so replicas with drifting clocks still agree
+// Every TAT expires once it is in the past, so clients that went away don't use any memory
+
+use async_trait::async_trait;
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Client, Script,
+};
+use std::{error::Error, time::Duration};
+use tokio::sync::OnceCell;
+
+use super::{Outcome, Quota, RateLimitStore};
+
+// The same algorithm as `gcra`, in microseconds
+// Returns whether the request is allowed, and the backlog in microseconds
+const GCRA: &str = r"
+local emission = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+
+local tat = tonumber(redis.call('GET', KEYS[1])) or now
+if tat < now then
+    tat = now
+end
+local new_tat = tat + emission
+if new_tat - now > burst then
+    return {0, tat - now}
+end
+-- Lua would write a number this large in exponent notation, losing the last digits
+redis.call('SET', KEYS[1], string.format('%d', new_tat), 'PX', math.ceil((new_tat - now) / 1000))
+return {1, new_tat - now}
+";
+
+// Every request waits on Redis, so when it is slow or down, we give up quickly and let the request through
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A store that keeps TATs in Redis.
+pub struct RedisStore {
+    client: Client,
+    // Connected on first use; the connection manager reconnects by itself after that
+    connection: OnceCell<ConnectionManager>,
+    script: Script,
+}
+
+impl RedisStore {
+    /// A store for the Redis server at `url`, like `redis://localhost:6379`.
+    /// It doesn't connect until the first request is counted.
+    pub fn new(url: &str) -> Result<Self, String> {
+        let client = Client::open(url).map_err(|error| format!("invalid Redis URL: {error}"))?;
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            script: Script::new(GCRA),
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisStore {
+    async fn acquire(
+        &self,
+        key: &str,
+        quota: Quota,
+    ) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+        let mut connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(Some(TIMEOUT))
+                    .set_response_timeout(Some(TIMEOUT))
+                    .set_number_of_retries(1);
+                self.client.get_connection_manager_with_config(config)
+            })
+            .await?
+            .clone();
+
+        let (allowed, backlog_us): (u8, u64) = self
+            .script
+            .key(key)
+            .arg(quota.emission_us())
+            .arg(quota.burst_us())
+            .invoke_async(&mut connection)
+            .await?;
+        Ok(Outcome {
+            allowed: allowed == 1,
+            backlog_us,
+        })
+    }
+}
@@ -0,0 +1,86 @@
+// Request ids, to match a client's request with our logs
+// If the client (or a proxy in front of us) sends `X-Request-Id`, we keep it, otherwise we generate a UUIDv7
+// The id is recorded on the request span, available to handlers through the `RequestId` extractor,
+// and echoed back on the response, so it can be quoted in support tickets
+
+use axum::{
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::fmt;
+
+use crate::error::AppError;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Incoming ids longer than this are replaced, so clients can't bloat our logs
+const MAX_LENGTH: usize = 128;
+
+tokio::task_local! {
+    // The id of the request being handled by the current task, for code that has no access to the request
+    static CURRENT: RequestId;
+}
+
+/// The id of the current request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn generate() -> Self {
+        // Version 7 UUIDs start with a timestamp, so they sort in the order they were created
+        Self(uuid::Uuid::now_v7().to_string())
+    }
+
+    /// The id of the request being handled, if called while handling one.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .ok_or_else(|| AppError::Internal("request id middleware is not installed".into()))
+    }
+}
+
+/// Middleware that assigns every request an id and echoes it on the response.
+pub async fn middleware(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid(value))
+        .map(|value| RequestId(value.to_owned()))
+        .unwrap_or_else(RequestId::generate);
+
+    request.extensions_mut().insert(id.clone());
+    let mut response = CURRENT.scope(id.clone(), next.run(request)).await;
+
+    // Valid ids are visible ASCII, so they always make a valid header value
+    if let Ok(value) = HeaderValue::from_str(id.as_str()) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+    response
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LENGTH && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
@@ -0,0 +1,342 @@
+// Security headers, telling browsers to lock down what they do with our responses
+//   Strict-Transport-Security   only talk to us over HTTPS from now on
+//   X-Content-Type-Options      trust our `Content-Type`, instead of sniffing what a body looks like
+//   Referrer-Policy             what to send in `Referer` when following a link away from us
+//   Permissions-Policy          browser features (camera, geolocation, ...) our pages may use
+//   X-Frame-Options             whether other sites may put our pages in a frame
+//   Content-Security-Policy     where our pages may load scripts, styles, images and so on from
+//
+// Every header has a default in our config, under `security_headers.*`, and can be turned `off`
+// Handlers that need something else set the header themselves; the middleware never replaces a header that is already there
+//
+// Every request gets a fresh random nonce, which handlers take with the `CspNonce` extractor
+// A policy with `Source::Nonce` in it lets in the inline scripts and styles marked `nonce="..."` with that value, and nothing else inline
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::{fmt, str::FromStr, sync::Arc};
+
+use crate::{config::Config, error::AppError};
+
+/// A source in a Content-Security-Policy directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Nothing at all, written `'none'`.
+    None,
+    /// Our own origin, written `'self'`.
+    SelfOrigin,
+    /// The request's nonce, written `'nonce'` in our config, and sent as `'nonce-<value>'`.
+    Nonce,
+    /// Scripts loaded by an allowed script, written `'strict-dynamic'`.
+    StrictDynamic,
+    /// Any inline script or style, written `'unsafe-inline'`. Ignored by browsers when the directive also has a nonce.
+    UnsafeInline,
+    /// `eval` and friends, written `'unsafe-eval'`.
+    UnsafeEval,
+    /// A scheme, like `https:`, `data:` or `blob:`.
+    Scheme(String),
+    /// A host, like `https://cdn.example.com` or `*.example.com`.
+    Host(String),
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let source = match s {
+            "'none'" => Source::None,
+            "'self'" => Source::SelfOrigin,
+            "'nonce'" => Source::Nonce,
+            "'strict-dynamic'" => Source::StrictDynamic,
+            "'unsafe-inline'" => Source::UnsafeInline,
+            "'unsafe-eval'" => Source::UnsafeEval,
+            keyword if keyword.starts_with('\'') => {
+                return Err(format!("unknown source {keyword}"));
+            }
+            scheme
+                if scheme.strip_suffix(':').is_some_and(|name| {
+                    !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+                }) =>
+            {
+                Source::Scheme(scheme.to_owned())
+            }
+            // Hosts end up in a header, between `;` separated directives
+            host if host
+                .chars()
+                .all(|c| c.is_ascii_graphic() && !";,".contains(c)) =>
+            {
+                Source::Host(host.to_owned())
+            }
+            _ => return Err(format!("invalid source {s:?}")),
+        };
+        Ok(source)
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::None => f.write_str("'none'"),
+            Source::SelfOrigin => f.write_str("'self'"),
+            Source::Nonce => f.write_str("'nonce'"),
+            Source::StrictDynamic => f.write_str("'strict-dynamic'"),
+            Source::UnsafeInline => f.write_str("'unsafe-inline'"),
+            Source::UnsafeEval => f.write_str("'unsafe-eval'"),
+            Source::Scheme(scheme) => f.write_str(scheme),
+            Source::Host(host) => f.write_str(host),
+        }
+    }
+}
+
+/// A Content-Security-Policy, built one directive at a time, like
+/// `ContentSecurityPolicy::new().default_src([Source::SelfOrigin]).script_src([Source::Nonce])`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContentSecurityPolicy {
+    directives: Vec<(String, Vec<Source>)>,
+}
+
+impl ContentSecurityPolicy {
+    /// A policy without any directive, which allows everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Our default policy: nothing may be loaded, framed or submitted, which suits responses that aren't pages.
+    pub fn strict() -> Self {
+        Self::new()
+            .default_src([Source::None])
+            .base_uri([Source::None])
+            .form_action([Source::None])
+            .frame_ancestors([Source::None])
+    }
+
+    /// Sets the directive `name`, replacing it if it was already set.
+    pub fn directive(mut self, name: &str, sources: impl IntoIterator<Item = Source>) -> Self {
+        let sources = sources.into_iter().collect();
+        match self
+            .directives
+            .iter_mut()
+            .find(|(existing, _)| existing == name)
+        {
+            Some((_, existing)) => *existing = sources,
+            None => self.directives.push((name.to_owned(), sources)),
+        }
+        self
+    }
+
+    pub fn default_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("default-src", sources)
+    }
+
+    pub fn script_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("script-src", sources)
+    }
+
+    pub fn style_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("style-src", sources)
+    }
+
+    pub fn img_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("img-src", sources)
+    }
+
+    pub fn font_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("font-src", sources)
+    }
+
+    pub fn connect_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("connect-src", sources)
+    }
+
+    pub fn worker_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("worker-src", sources)
+    }
+
+    pub fn object_src(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("object-src", sources)
+    }
+
+    pub fn base_uri(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("base-uri", sources)
+    }
+
+    pub fn form_action(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("form-action", sources)
+    }
+
+    /// Who may put our pages in a frame. Browsers that know this directive ignore `X-Frame-Options`.
+    pub fn frame_ancestors(self, sources: impl IntoIterator<Item = Source>) -> Self {
+        self.directive("frame-ancestors", sources)
+    }
+
+    /// The header value for a request with `nonce`.
+    pub fn render(&self, nonce: &CspNonce) -> String {
+        self.directives
+            .iter()
+            .map(|(name, sources)| {
+                let mut directive = name.clone();
+                for source in sources {
+                    directive.push(' ');
+                    match source {
+                        Source::Nonce => directive.push_str(&format!("'nonce-{nonce}'")),
+                        source => directive.push_str(&source.to_string()),
+                    }
+                }
+                directive
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+// Written like the header, with `'nonce'` where the request's nonce goes:
+// `default-src 'self'; script-src 'nonce' 'strict-dynamic'`
+impl FromStr for ContentSecurityPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = Self::new();
+        for directive in s.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.split_whitespace();
+            let name = parts.next().unwrap_or_default();
+            if !name.chars().all(|c| c.is_ascii_lowercase() || c == '-') {
+                return Err(format!("invalid directive name {name:?}"));
+            }
+            let sources = parts
+                .map(str::parse)
+                .collect::<Result<Vec<Source>, String>>()?;
+            policy = policy.directive(name, sources);
+        }
+        if policy.directives.is_empty() {
+            return Err("expected a policy like default-src 'self'".into());
+        }
+        Ok(policy)
+    }
+}
+
+/// Parses a policy from our config, where `off` means no policy.
+pub fn parse_policy(value: &str) -> Result<Option<ContentSecurityPolicy>, String> {
+    match value {
+        "off" => Ok(None),
+        policy => policy.parse().map(Some),
+    }
+}
+
+/// Parses a header value from our config, where `off` means the header isn't sent.
+pub fn parse_header(value: &str) -> Result<Option<HeaderValue>, String> {
+    match value {
+        "off" => Ok(None),
+        value => HeaderValue::from_str(value)
+            .map(Some)
+            .map_err(|_| "expected a valid header value, or off".into()),
+    }
+}
+
+/// The nonce of the current request, for `nonce="..."` attributes on the inline scripts and styles of a page.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    fn generate() -> Self {
+        // 122 random bits, as 32 hex digits, which are valid in a nonce
+        Self(uuid::Uuid::new_v4().simple().to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for CspNonce {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<CspNonce>().cloned().ok_or_else(|| {
+            AppError::Internal("security headers middleware is not installed".into())
+        })
+    }
+}
+
+/// The headers from our config, added to every response that doesn't have them yet.
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    content_security_policy: Option<ContentSecurityPolicy>,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &Config) -> Self {
+        let headers = [
+            (
+                header::STRICT_TRANSPORT_SECURITY,
+                config.security_headers_hsts.clone(),
+            ),
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                Some(HeaderValue::from_static("nosniff")),
+            ),
+            (
+                header::REFERRER_POLICY,
+                config.security_headers_referrer_policy.clone(),
+            ),
+            (
+                HeaderName::from_static("permissions-policy"),
+                config.security_headers_permissions_policy.clone(),
+            ),
+            (
+                header::X_FRAME_OPTIONS,
+                config.security_headers_frame_options.clone(),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect();
+
+        Self {
+            headers,
+            content_security_policy: config.security_headers_content_security_policy.clone(),
+        }
+    }
+}
+
+/// Middleware that gives every request a nonce, and adds our security headers to its response.
+pub async fn middleware(
+    State(security_headers): State<Arc<SecurityHeaders>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let nonce = CspNonce::generate();
+    request.extensions_mut().insert(nonce.clone());
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    for (name, value) in &security_headers.headers {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    if let Some(policy) = &security_headers.content_security_policy {
+        if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+            // Sources are checked when they are parsed, but ones built in code could still hold anything
+            match HeaderValue::try_from(policy.render(&nonce)) {
+                Ok(value) => {
+                    headers.insert(header::CONTENT_SECURITY_POLICY, value);
+                }
+                Err(_) => tracing::warn!("The Content-Security-Policy isn't a valid header value"),
+            }
+        }
+    }
+    response
+}
@@ -0,0 +1,175 @@
+// Graceful shutdown for the server
+// When the platform redeploys us, it sends SIGTERM and waits a little while before killing the process
+// We use that window to fail readiness checks, so the platform stops sending us traffic,
+// then to stop accepting new connections, let in-flight requests finish ("draining"),
+// and, if they take too long, give up and log exactly which requests we abandoned
+// Readiness checks are polled, so we keep accepting connections for a while after the signal (`shutdown_delay`):
+// closing the listeners right away would refuse requests the platform still sends us before it notices
+
+use axum::{extract::Request, extract::State, middleware::Next, response::Response};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+use crate::request_id::RequestId;
+
+/// Shared shutdown coordinator.
+///
+/// Cloning is cheap; every clone observes the same signal, readiness flag and in-flight table.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    draining: AtomicBool,
+    notify: Notify,
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+}
+
+// What we remember about a request while it is being handled
+struct InFlight {
+    request_id: Option<RequestId>,
+    method: String,
+    uri: String,
+    started: Instant,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns `true` once a shutdown signal has been received.
+    ///
+    /// Readiness checks use this to report "not ready" so the platform stops routing new traffic to us.
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    /// Starts draining. Calling this more than once has no further effect.
+    pub fn trigger(&self) {
+        if !self.inner.draining.swap(true, Ordering::SeqCst) {
+            self.inner.notify.notify_waiters();
+        }
+    }
+
+    /// Resolves once [`Shutdown::trigger`] has been called.
+    pub async fn triggered(&self) {
+        // Register interest before checking the flag, so we can't miss a trigger in between
+        let notified = self.inner.notify.notified();
+        if self.is_draining() {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Waits for SIGINT (Ctrl+C) or SIGTERM, then starts draining.
+    pub async fn listen_for_signals(self) {
+        let ctrl_c = async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("failed to install Ctrl+C handler");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to install SIGTERM handler")
+                .recv()
+                .await;
+        };
+
+        // Windows has no SIGTERM, so we only listen for Ctrl+C there
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => tracing::info!("Received SIGINT, starting graceful shutdown"),
+            _ = terminate => tracing::info!("Received SIGTERM, starting graceful shutdown"),
+            // Someone else may have triggered shutdown already, in which case we stop waiting
+            _ = self.triggered() => return,
+        }
+
+        self.trigger();
+    }
+
+    /// Resolves `drain_timeout` after shutdown was triggered.
+    ///
+    /// Racing the server against this future gives us a hard cutoff for draining.
+    pub async fn deadline(&self, drain_timeout: Duration) {
+        self.triggered().await;
+        tokio::time::sleep(drain_timeout).await;
+    }
+
+    /// Logs every request that is still being handled. Called when the drain deadline passes.
+    pub fn log_abandoned(&self) {
+        let in_flight = self.inner.in_flight.lock().unwrap();
+        tracing::warn!(
+            "Drain deadline exceeded, abandoning {} in-flight request(s)",
+            in_flight.len()
+        );
+        for request in in_flight.values() {
+            tracing::warn!(
+                request_id = request.request_id.as_ref().map(RequestId::as_str),
+                method = %request.method,
+                uri = %request.uri,
+                elapsed_ms = request.started.elapsed().as_millis() as u64,
+                "Abandoned request"
+            );
+        }
+    }
+
+    // Records a request as in-flight until the returned guard is dropped
+    fn track(&self, request: &Request) -> InFlightGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner.in_flight.lock().unwrap().insert(
+            id,
+            InFlight {
+                request_id: request.extensions().get::<RequestId>().cloned(),
+                method: request.method().to_string(),
+                uri: request.uri().to_string(),
+                started: Instant::now(),
+            },
+        );
+        InFlightGuard {
+            shutdown: self.clone(),
+            id,
+        }
+    }
+}
+
+// Removes the request from the in-flight table when it completes (or is cancelled)
+struct InFlightGuard {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.shutdown
+            .inner
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
+
+/// Middleware that keeps track of in-flight requests, so they can be reported if draining times out.
+pub async fn track_in_flight(
+    State(shutdown): State<Shutdown>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let _guard = shutdown.track(&request);
+    next.run(request).await
+}
@@ -0,0 +1,167 @@
+// Shared application state
+// `AppState` is built once at startup from our config, and holds everything handlers share:
+// the database pool, an in-memory cache, an HTTP client for calling other services, feature flags,
+// the rate limiter, whose counts must outlive any one router, and the authenticator, which checks bearer tokens
+// Handlers take the whole state with `State<AppState>`, or just the part they need, like `State<FeatureFlags>`
+// Every field is public, so integration tests can swap any of them for a test double
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, StatusCode},
+};
+use reqwest_middleware::ClientWithMiddleware;
+use sqlx::{postgres::PgPoolOptions, PgPool};
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    auth::Authenticator, cache::Cache, config::Config, error::AppError, health::HealthCheck,
+    rate_limit::RateLimiter, telemetry::PropagateTraceContext,
+};
+
+/// Everything our handlers share.
+///
+/// Cloning is cheap; every clone shares the same resources.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    /// The database pool, or `None` when no database is configured.
+    pub database: Option<Database>,
+    /// A general purpose cache, for anything that is expensive to compute or fetch.
+    pub cache: Cache<String, serde_json::Value>,
+    /// A client for calling other services; it shares connections between requests,
+    /// and passes the current trace on (see `telemetry.rs`).
+    pub http: ClientWithMiddleware,
+    pub features: FeatureFlags,
+    /// Counts requests against our rate limits, see `rate_limit.rs`.
+    pub rate_limiter: RateLimiter,
+    /// Checks bearer tokens, see `auth.rs`.
+    pub auth: Authenticator,
+}
+
+impl AppState {
+    /// Builds the state from our config.
+    /// The database pool connects lazily, so this doesn't fail when the database is down.
+    /// The rustls crypto provider must be installed first, see `tls::install_crypto_provider`.
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let database = config
+            .database_url
+            .as_ref()
+            .map(|url| {
+                PgPoolOptions::new()
+                    .max_connections(config.database_max_connections)
+                    .acquire_timeout(config.database_acquire_timeout)
+                    .connect_lazy(url.expose())
+                    .map(Database)
+                    .map_err(|error| format!("invalid database URL: {error}"))
+            })
+            .transpose()?;
+
+        // The HTTP client uses the same TLS implementation as our server, see `tls::install_crypto_provider`
+        let http = reqwest::Client::builder()
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .timeout(config.http_client_timeout)
+            .build()
+            .map_err(|error| format!("failed to create HTTP client: {error}"))?;
+        let http = reqwest_middleware::ClientBuilder::new(http)
+            .with(PropagateTraceContext)
+            .build();
+
+        let cache = Cache::new(config.cache_ttl, config.cache_max_entries);
+        Ok(Self {
+            config: Arc::new(config.clone()),
+            database,
+            auth: Authenticator::new(config, http.clone()),
+            cache,
+            http,
+            features: FeatureFlags::new(config.features.iter().cloned()),
+            rate_limiter: RateLimiter::new(config)?,
+        })
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Cache<String, serde_json::Value> {
+    fn from_ref(state: &AppState) -> Self {
+        state.cache.clone()
+    }
+}
+
+impl FromRef<AppState> for ClientWithMiddleware {
+    fn from_ref(state: &AppState) -> Self {
+        state.http.clone()
+    }
+}
+
+impl FromRef<AppState> for Authenticator {
+    fn from_ref(state: &AppState) -> Self {
+        state.auth.clone()
+    }
+}
+
+impl FromRef<AppState> for FeatureFlags {
+    fn from_ref(state: &AppState) -> Self {
+        state.features.clone()
+    }
+}
+
+/// A pool of database connections.
+///
+/// As an extractor, it rejects the request with a 503 when no database is configured.
+#[derive(Clone, Debug)]
+pub struct Database(pub PgPool);
+
+impl<S> FromRequestParts<S> for Database
+where
+    AppState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(_parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        AppState::from_ref(state).database.ok_or_else(|| {
+            AppError::Status(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "no database is configured".into(),
+            )
+        })
+    }
+}
+
+#[async_trait]
+impl HealthCheck for Database {
+    fn name(&self) -> &str {
+        "database"
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        sqlx::query("SELECT 1")
+            .execute(&self.0)
+            .await
+            .map(|_| ())
+            .map_err(|error| error.to_string())
+    }
+}
+
+/// The names of the enabled feature flags.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlags(Arc<HashSet<String>>);
+
+impl FeatureFlags {
+    pub fn new(enabled: impl IntoIterator<Item = String>) -> Self {
+        Self(Arc::new(enabled.into_iter().collect()))
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+}
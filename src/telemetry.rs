@@ -0,0 +1,169 @@
+// OpenTelemetry trace export
+// When `otel.endpoint` is set, our tracing spans are also exported over OTLP to a collector, using gRPC or HTTP
+// Incoming W3C `traceparent`/`tracestate` headers make our request spans children of the caller's trace,
+// and `inject` adds the same headers to outgoing requests, so the trace continues downstream
+// Our shared HTTP client does that for every request it sends, with the `PropagateTraceContext` middleware
+// Spans are exported in batches, so anything still buffered is flushed when we shut down
+
+use async_trait::async_trait;
+use axum::http::{Extensions, HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    propagation::{Extractor, Injector},
+    trace::TracerProvider as _,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+    Resource,
+};
+use std::str::FromStr;
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::Config;
+
+/// How spans are sent to the collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC, usually on port 4317.
+    Grpc,
+    /// OTLP over HTTP with protobuf bodies, usually on port 4318.
+    Http,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grpc" => Ok(OtlpProtocol::Grpc),
+            "http" => Ok(OtlpProtocol::Http),
+            _ => Err("expected grpc or http".into()),
+        }
+    }
+}
+
+/// The OpenTelemetry tracer provider, when trace export is enabled.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Sets up trace export, or returns `None` when no collector endpoint is configured.
+    pub fn init(config: &Config) -> Result<Option<Self>, String> {
+        let Some(endpoint) = &config.otel_endpoint else {
+            return Ok(None);
+        };
+
+        let exporter = match config.otel_protocol {
+            OtlpProtocol::Grpc => SpanExporter::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build(),
+            // The HTTP exporter wants the full URL of the traces endpoint
+            OtlpProtocol::Http => SpanExporter::builder()
+                .with_http()
+                .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+                .build(),
+        }
+        .map_err(|error| format!("failed to create OTLP exporter: {error}"))?;
+
+        // We follow the caller's sampling decision when there is one, and sample new traces at the configured ratio
+        let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.otel_sampling_ratio,
+        )));
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(sampler)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.log_service.clone())
+                    .build(),
+            )
+            .build();
+
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        Ok(Some(Self { provider }))
+    }
+
+    /// A tracing layer that turns our spans into OpenTelemetry spans.
+    pub fn layer<S>(&self) -> OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer(env!("CARGO_PKG_NAME")))
+    }
+
+    /// Exports any spans that are still buffered, and stops the exporter.
+    pub async fn shutdown(self) {
+        // Flushing blocks until the collector has answered, which mustn't hold up a runtime thread
+        let result = tokio::task::spawn_blocking(move || self.provider.shutdown()).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => tracing::error!("Failed to flush OpenTelemetry spans: {}", error),
+            Err(error) => tracing::error!("Failed to flush OpenTelemetry spans: {}", error),
+        }
+    }
+}
+
+/// Makes `span` a child of the trace described by the `traceparent`/`tracestate` headers, if any.
+pub fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    // Without export enabled, spans have no OpenTelemetry data and this is a no-op
+    let _ = span.set_parent(context);
+}
+
+/// Adds the `traceparent`/`tracestate` headers for the current span, for an outgoing request.
+pub fn inject(headers: &mut HeaderMap) {
+    let context = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Middleware for our HTTP client, which adds the current trace to every outgoing request with `inject`.
+pub struct PropagateTraceContext;
+
+#[async_trait]
+impl reqwest_middleware::Middleware for PropagateTraceContext {
+    async fn handle(
+        &self,
+        mut request: reqwest::Request,
+        extensions: &mut Extensions,
+        next: reqwest_middleware::Next<'_>,
+    ) -> reqwest_middleware::Result<reqwest::Response> {
+        inject(request.headers_mut());
+        next.run(request, extensions).await
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
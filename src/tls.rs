@@ -0,0 +1,247 @@
+// HTTPS termination with rustls
+// Normally the platform's edge proxy terminates TLS for us, but when running elsewhere we can do it ourselves
+// The certificate and key are read from PEM files, and reloaded whenever they change on disk
+// Reloading only affects new handshakes: connections that are already open keep the certificate they started with
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    InconsistentKeys, ServerConfig,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use crate::shutdown::Shutdown;
+
+// Clients that don't finish the handshake in time are disconnected, so they can't tie up resources
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How many finished handshakes may wait for the server to pick them up
+const ACCEPT_QUEUE: usize = 128;
+
+/// Makes ring the process-wide rustls crypto provider.
+/// HTTP clients with rustls, like ours and the trace exporter's, can't be built without one,
+/// so this is called once at startup, before anything else.
+pub fn install_crypto_provider() {
+    // A provider that is already installed, by whoever embeds us, serves our clients just as well
+    let _ = rustls::crypto::ring::default_provider().install_default();
+}
+
+/// Shared TLS settings for every HTTPS listener.
+#[derive(Clone)]
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    resolver: Arc<ReloadingCert>,
+}
+
+impl Tls {
+    /// Loads the certificate chain and private key, failing if either can't be read.
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let resolver = Arc::new(ReloadingCert {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            modified: RwLock::new(modified(cert_path, key_path)),
+        });
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        // Advertise HTTP/2 first, so clients that support it will use it
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            resolver,
+        })
+    }
+
+    /// Checks the certificate files every `interval`, reloading them when they change.
+    /// Stops once `shutdown` is triggered.
+    pub fn watch(&self, interval: Duration, shutdown: Shutdown) {
+        let resolver = self.resolver.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => resolver.reload_if_changed(),
+                    _ = shutdown.triggered() => return,
+                }
+            }
+        });
+    }
+
+    /// Wraps a TCP listener so every accepted connection completes a TLS handshake first.
+    pub fn listener(&self, listener: TcpListener) -> io::Result<TlsListener> {
+        let local_addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel(ACCEPT_QUEUE);
+        tokio::spawn(accept_loop(listener, self.acceptor.clone(), sender));
+        Ok(TlsListener {
+            receiver,
+            local_addr,
+        })
+    }
+}
+
+// Hands out the current certificate, which the watcher replaces when the files change
+#[derive(Debug)]
+struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: RwLock<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReloadingCert {
+    fn reload_if_changed(&self) {
+        let modified = modified(&self.cert_path, &self.key_path);
+        if modified.is_none() || modified == *self.modified.read().unwrap() {
+            return;
+        }
+
+        // Certificates are often renewed by writing the two files one after the other,
+        // so a failed load is retried on the next tick while we keep serving the old certificate
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = Arc::new(key);
+                *self.modified.write().unwrap() = modified;
+                tracing::info!("Reloaded TLS certificate from {}", self.cert_path.display());
+            }
+            Err(error) => tracing::error!("Failed to reload TLS certificate: {}", error),
+        }
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = std::fs::metadata(cert_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    let key = std::fs::metadata(key_path)
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some((cert, key))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let invalid = |path: &Path, error: rustls_pki_types::pem::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), error),
+        )
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|error| invalid(cert_path, error))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", cert_path.display()),
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|error| invalid(key_path, error))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", key_path.display(), error),
+        )
+    })?;
+
+    // A key from another certificate would only show up as failed handshakes, so we refuse to load it
+    let certified = CertifiedKey::new(certs, key);
+    match certified.keys_match() {
+        // Some keys can't tell us their public half, so there's nothing to compare
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+        Err(error) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} doesn't belong to {}: {}",
+                    key_path.display(),
+                    cert_path.display(),
+                    error
+                ),
+            ))
+        }
+    }
+    Ok(certified)
+}
+
+/// A listener that yields connections once their TLS handshake has completed.
+///
+/// Handshakes run in their own tasks, so one slow client can't hold up everyone else.
+pub struct TlsListener {
+    receiver: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.receiver.recv().await {
+            Some(connection) => connection,
+            // The accept loop only stops once we've been dropped, so this can't happen
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    // Errors like running out of file descriptors are usually temporary, so we back off and retry
+                    tracing::error!("Failed to accept connection: {}", error);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            // The `TlsListener` was dropped, so the server has stopped
+            _ = sender.closed() => return,
+        };
+
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = sender.send((stream, addr)).await;
+                }
+                Ok(Err(error)) => tracing::debug!(%addr, "TLS handshake failed: {}", error),
+                Err(_) => tracing::debug!(%addr, "TLS handshake timed out"),
+            }
+        });
+    }
+}
@@ -0,0 +1,208 @@
+// API versions
+// Each version of our API is a router nested under its own path prefix, like `/v1/complex` and `/v2/complex`,
+// so a response contract can change in a new version without breaking clients of the old one
+// A handler that didn't change is simply added to every version's router, instead of being copied
+//
+// Old versions are retired in two steps:
+//   once deprecated, responses carry a `Deprecation` header (RFC 9745), and a `Link` to the successor version
+//   once a sunset date is set, responses carry a `Sunset` header (RFC 8594), and after that date the version answers 410 Gone
+// Operations of deprecated versions are also marked as deprecated in our OpenAPI document
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use time::{macros::format_description, OffsetDateTime};
+use utoipa::openapi::Deprecated;
+use utoipa_axum::router::OpenApiRouter;
+
+use crate::error::Problem;
+
+static DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+static SUNSET: HeaderName = HeaderName::from_static("sunset");
+static LINK: HeaderName = HeaderName::from_static("link");
+
+/// A version of our API, and where it is in its lifecycle.
+#[derive(Debug, Clone)]
+pub struct ApiVersion {
+    /// The name, which is also the path prefix, like `v1`.
+    pub name: &'static str,
+    /// When the version was deprecated. Clients should move to `successor`.
+    pub deprecated: Option<OffsetDateTime>,
+    /// When the version stops working.
+    pub sunset: Option<OffsetDateTime>,
+    /// The version clients should move to, like `v2`.
+    pub successor: Option<&'static str>,
+}
+
+impl ApiVersion {
+    /// A current version, with no deprecation or sunset date.
+    pub fn current(name: &'static str) -> Self {
+        Self {
+            name,
+            deprecated: None,
+            sunset: None,
+            successor: None,
+        }
+    }
+}
+
+/// Nests `routes` under `/<version.name>`, with the version's lifecycle headers,
+/// and adds them to our OpenAPI document.
+/// Fails if the version's headers can't be built, like a `successor` that can't go in a `Link` header.
+pub fn nest<S>(
+    app: OpenApiRouter<S>,
+    version: ApiVersion,
+    routes: OpenApiRouter<S>,
+) -> Result<OpenApiRouter<S>, String>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let prefix = format!("/{}", version.name);
+    let routes = with_lifecycle(version, routes, true)?;
+    Ok(app.nest(&prefix, routes))
+}
+
+/// Adds `routes` without a prefix, as aliases of the same routes in `version`, with its lifecycle headers.
+/// This keeps paths from before the API was versioned working, like `/complex` for `/v1/complex`.
+pub fn alias<S>(
+    app: OpenApiRouter<S>,
+    version: ApiVersion,
+    routes: OpenApiRouter<S>,
+) -> Result<OpenApiRouter<S>, String>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Ok(app.merge(with_lifecycle(version, routes, false)?))
+}
+
+// Marks the operations of `routes` in our document, and adds the lifecycle middleware
+fn with_lifecycle<S>(
+    version: ApiVersion,
+    mut routes: OpenApiRouter<S>,
+    versioned_ids: bool,
+) -> Result<OpenApiRouter<S>, String>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let document = routes.get_openapi_mut();
+    for item in document.paths.paths.values_mut() {
+        let operations = [
+            &mut item.get,
+            &mut item.put,
+            &mut item.post,
+            &mut item.delete,
+            &mut item.options,
+            &mut item.head,
+            &mut item.patch,
+            &mut item.trace,
+        ];
+        for operation in operations.into_iter().flatten() {
+            // Operation ids must be unique across the document, and the same handler can be in several versions
+            if versioned_ids {
+                operation.operation_id = operation
+                    .operation_id
+                    .take()
+                    .map(|id| format!("{}_{id}", version.name));
+            }
+            if version.deprecated.is_some() {
+                operation.deprecated = Some(Deprecated::True);
+            }
+        }
+    }
+
+    if version.deprecated.is_none() && version.sunset.is_none() {
+        return Ok(routes);
+    }
+    let lifecycle = Lifecycle::new(version)?;
+    Ok(routes.layer(middleware::from_fn_with_state(
+        Arc::new(lifecycle),
+        lifecycle_headers,
+    )))
+}
+
+// A version's lifecycle, with its headers ready to add to every response
+struct Lifecycle {
+    name: &'static str,
+    successor: Option<&'static str>,
+    sunset: Option<OffsetDateTime>,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Lifecycle {
+    fn new(version: ApiVersion) -> Result<Self, String> {
+        let invalid = |header: &str| format!("invalid {header} header for API {}", version.name);
+        let mut headers = Vec::new();
+        if let Some(deprecated) = version.deprecated {
+            // A structured field date: `@` followed by seconds since the epoch
+            let value = HeaderValue::from_str(&format!("@{}", deprecated.unix_timestamp()))
+                .map_err(|_| invalid("Deprecation"))?;
+            headers.push((DEPRECATION.clone(), value));
+        }
+        if let Some(sunset) = version.sunset {
+            headers.push((
+                SUNSET.clone(),
+                http_date(sunset).ok_or_else(|| invalid("Sunset"))?,
+            ));
+        }
+        if let Some(successor) = version.successor {
+            let value =
+                HeaderValue::from_str(&format!("</{successor}>; rel=\"successor-version\""))
+                    .map_err(|_| invalid("Link"))?;
+            headers.push((LINK.clone(), value));
+        }
+
+        Ok(Self {
+            name: version.name,
+            successor: version.successor,
+            sunset: version.sunset,
+            headers,
+        })
+    }
+}
+
+// Adds the lifecycle headers, and turns requests away once the version is past its sunset date
+async fn lifecycle_headers(
+    State(lifecycle): State<Arc<Lifecycle>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = match lifecycle.sunset {
+        Some(sunset) if OffsetDateTime::now_utc() >= sunset => {
+            let mut problem = Problem::new(
+                StatusCode::GONE,
+                Some(format!("API {} is no longer available", lifecycle.name)),
+            );
+            if let Some(successor) = lifecycle.successor {
+                problem = problem.with("successor", format!("/{successor}"));
+            }
+            problem.into_response()
+        }
+        _ => next.run(request).await,
+    };
+
+    let headers = response.headers_mut();
+    for (name, value) in &lifecycle.headers {
+        // `Link` can already carry other links, so ours is added to them
+        if *name == LINK {
+            headers.append(name.clone(), value.clone());
+        } else {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    response
+}
+
+// An HTTP date, like `Sun, 06 Nov 1994 08:49:37 GMT`
+fn http_date(date: OffsetDateTime) -> Option<HeaderValue> {
+    let date = date.to_offset(time::UtcOffset::UTC);
+    let formatted = date
+        .format(format_description!(
+            "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
+        ))
+        .ok()?;
+    HeaderValue::from_str(&formatted).ok()
+}
@@ -0,0 +1,77 @@
+// Access log lines, in the Common and Combined Log Formats
+
+mod support;
+
+use support::{capture_logs, TestApp};
+
+// The line for the one request in `logs`, without the timestamp between the brackets
+fn access_line(logs: &str) -> String {
+    let line = logs
+        .lines()
+        .find(|line| line.contains(" - - ["))
+        .unwrap_or_else(|| panic!("no access log line in {logs}"));
+    let start = line.find(" - - [").unwrap();
+    let client = line[..start].rsplit(' ').next().unwrap();
+    let rest = &line[line.find("] ").unwrap() + 2..];
+    format!("{client} - - [] {rest}")
+}
+
+#[tokio::test]
+async fn common_lines_name_the_peer() {
+    let (logs, _guard) = capture_logs();
+    let app = TestApp::with_args(["--access-log-format", "common"]);
+
+    // Without trusted proxies, `X-Forwarded-For` could be anything
+    app.get("/")
+        .peer("203.0.113.7:40000")
+        .header("x-forwarded-for", "198.51.100.1")
+        .send()
+        .await;
+
+    assert_eq!(
+        access_line(&logs.contents()),
+        r#"203.0.113.7 - - [] "GET / HTTP/1.1" 200 13"#
+    );
+}
+
+#[tokio::test]
+async fn combined_lines_add_the_referer_and_user_agent() {
+    let (logs, _guard) = capture_logs();
+    let app = TestApp::with_args([
+        "--access-log-format",
+        "combined",
+        "--rate-limit-trusted-proxies",
+        "10.0.0.0/8",
+    ]);
+
+    app.get("/")
+        .peer("10.0.0.2:40000")
+        .header("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.1")
+        .header("referer", "https://example.com/")
+        .header("user-agent", "curl/8.0 \"quoted\"")
+        .send()
+        .await;
+
+    assert_eq!(
+        access_line(&logs.contents()),
+        r#"203.0.113.7 - - [] "GET / HTTP/1.1" 200 13 "https://example.com/" "curl/8.0 \"quoted\"""#
+    );
+}
+
+#[tokio::test]
+async fn excluded_paths_arent_logged() {
+    let (logs, _guard) = capture_logs();
+    let app = TestApp::with_args([
+        "--access-log-format",
+        "common",
+        "--access-log-exclude",
+        "/complex",
+    ]);
+
+    app.get("/complex").send().await;
+    app.get("/").send().await;
+
+    let logs = logs.contents();
+    assert!(!logs.contains("GET /complex"), "{logs}");
+    assert!(logs.contains("GET / HTTP/1.1"), "{logs}");
+}
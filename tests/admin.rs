@@ -0,0 +1,170 @@
+// The admin API, which changes the log filter at runtime
+
+mod support;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use rust_starter::{config::Config, logging::LogFilter, tls};
+use serde_json::json;
+use std::time::Duration;
+use support::TestApp;
+use tower::ServiceExt;
+
+const TOKEN: &str = "an-admin-token";
+
+fn app() -> TestApp {
+    TestApp::with_args(["--admin-token", TOKEN, "--log-filter", "info"])
+}
+
+#[tokio::test]
+async fn admin_routes_need_the_token() {
+    let app = app();
+
+    app.get("/admin/log-filter")
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED)
+        .assert_header("www-authenticate", "Bearer");
+    app.get("/admin/log-filter")
+        .bearer("not-the-token")
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn admin_routes_arent_mounted_without_a_token() {
+    TestApp::new()
+        .get("/admin/log-filter")
+        .send()
+        .await
+        .assert_problem(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn the_log_filter_can_be_read_and_replaced() {
+    let app = app();
+
+    app.get("/admin/log-filter")
+        .bearer(TOKEN)
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "directives": "info" }));
+    app.put("/admin/log-filter")
+        .bearer(TOKEN)
+        .json(&json!({ "directives": "info,rust_starter=debug" }))
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "directives": "info,rust_starter=debug" }));
+    app.get("/admin/log-filter")
+        .bearer(TOKEN)
+        .send()
+        .await
+        .assert_json(json!({ "directives": "info,rust_starter=debug" }));
+}
+
+#[tokio::test]
+async fn invalid_directives_are_rejected() {
+    let app = app();
+
+    app.put("/admin/log-filter")
+        .bearer(TOKEN)
+        .json(&json!({ "directives": "rust_starter=loud" }))
+        .send()
+        .await
+        .assert_problem(StatusCode::BAD_REQUEST);
+    app.get("/admin/log-filter")
+        .bearer(TOKEN)
+        .send()
+        .await
+        .assert_json(json!({ "directives": "info" }));
+}
+
+// Moves the paused clock forward, yielding first so a just-spawned revert task starts its timer,
+// and after so a revert that has become due gets to run
+async fn wait(duration: Duration) {
+    tokio::task::yield_now().await;
+    tokio::time::advance(duration).await;
+    tokio::task::yield_now().await;
+}
+
+// Time is paused, so the reverts are due as soon as the clock is advanced past them
+#[tokio::test(start_paused = true)]
+async fn the_log_filter_reverts_unless_changed_again() {
+    let app = app();
+    let directives = || async {
+        app.get("/admin/log-filter")
+            .bearer(TOKEN)
+            .send()
+            .await
+            .json::<serde_json::Value>()["directives"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    };
+
+    app.put("/admin/log-filter")
+        .bearer(TOKEN)
+        .json(&json!({ "directives": "debug", "revert_after_secs": 1 }))
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "directives": "debug", "revert_after_secs": 1 }));
+    assert_eq!(directives().await, "debug");
+    wait(Duration::from_millis(1500)).await;
+    assert_eq!(directives().await, "info");
+
+    // A change made before the revert is due wins over it
+    app.put("/admin/log-filter")
+        .bearer(TOKEN)
+        .json(&json!({ "directives": "debug", "revert_after_secs": 1 }))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    app.put("/admin/log-filter")
+        .bearer(TOKEN)
+        .json(&json!({ "directives": "warn" }))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    wait(Duration::from_millis(1500)).await;
+    assert_eq!(directives().await, "warn");
+}
+
+#[tokio::test]
+async fn the_log_filter_can_be_changed_over_http() {
+    let server = app().serve().await;
+
+    server
+        .request(axum::http::Method::PUT, "/admin/log-filter")
+        .bearer(TOKEN)
+        .json(&json!({ "directives": "warn" }))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    server
+        .get("/admin/log-filter")
+        .bearer(TOKEN)
+        .send()
+        .await
+        .assert_json(json!({ "directives": "warn" }));
+}
+
+#[tokio::test]
+async fn build_app_mounts_the_admin_routes_with_a_log_filter() {
+    let config = Config::from_args(["--admin-token", TOKEN, "--log-filter", "info"]).unwrap();
+    tls::install_crypto_provider();
+    let (log_filter, _layer) = LogFilter::new(&config.log_filter).unwrap();
+    let router = rust_starter::build_app(&config, Some(log_filter)).unwrap();
+
+    let request = Request::get("/admin/log-filter")
+        .header("authorization", format!("Bearer {TOKEN}"))
+        .body(Body::empty())
+        .unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
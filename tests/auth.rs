@@ -0,0 +1,468 @@
+// Bearer JWT authentication, with a shared secret (HS256) and with keys from a JWKS (RS256 and EdDSA)
+
+mod support;
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use jsonwebtoken::{
+    jwk::{Jwk, JwkSet},
+    Algorithm, EncodingKey, Header,
+};
+use rust_starter::config::Config;
+use serde_json::{json, Value};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use support::TestApp;
+use tokio::net::TcpListener;
+
+const SECRET: &str = "an-hs256-secret-of-at-least-32-bytes";
+const RSA_KEY: &str = include_str!("fixtures/jwt-rsa.pem");
+const ED25519_KEY: &str = include_str!("fixtures/jwt-ed25519.pem");
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// Claims for `alice` that are valid for an hour, with `extra` added or replacing any of them
+fn claims(extra: Value) -> Value {
+    let mut claims = json!({ "sub": "alice", "exp": now() + 3600 });
+    claims
+        .as_object_mut()
+        .unwrap()
+        .extend(extra.as_object().unwrap().clone());
+    claims
+}
+
+fn hs256(claims: &Value) -> String {
+    jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        claims,
+        &EncodingKey::from_secret(SECRET.as_bytes()),
+    )
+    .unwrap()
+}
+
+fn rsa_key() -> EncodingKey {
+    EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap()
+}
+
+fn ed25519_key() -> EncodingKey {
+    EncodingKey::from_ed_pem(ED25519_KEY.as_bytes()).unwrap()
+}
+
+fn sign(key: &EncodingKey, alg: Algorithm, kid: &str, claims: &Value) -> String {
+    let mut header = Header::new(alg);
+    header.kid = Some(kid.to_owned());
+    jsonwebtoken::encode(&header, claims, key).unwrap()
+}
+
+fn jwk(key: &EncodingKey, alg: Algorithm, kid: &str) -> Jwk {
+    let mut jwk = Jwk::from_encoding_key(key, alg).unwrap();
+    jwk.common.key_id = Some(kid.to_owned());
+    jwk
+}
+
+// The state of an issuer: its current keys, how often they were fetched, and whether it is down
+type Published = (Arc<Mutex<JwkSet>>, Arc<AtomicUsize>, Arc<AtomicBool>);
+
+// An issuer's JWKS endpoint, which counts how often it is fetched
+struct Issuer {
+    url: String,
+    keys: Arc<Mutex<JwkSet>>,
+    fetches: Arc<AtomicUsize>,
+    down: Arc<AtomicBool>,
+}
+
+impl Issuer {
+    async fn start(keys: Vec<Jwk>) -> Self {
+        let keys = Arc::new(Mutex::new(JwkSet { keys }));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let down = Arc::new(AtomicBool::new(false));
+        let router = Router::new()
+            .route(
+                "/.well-known/jwks.json",
+                get(
+                    |State((keys, fetches, down)): State<Published>| async move {
+                        fetches.fetch_add(1, Ordering::Relaxed);
+                        if down.load(Ordering::Relaxed) {
+                            return Err(StatusCode::SERVICE_UNAVAILABLE);
+                        }
+                        let keys = keys.lock().unwrap().clone();
+                        Ok(Json(keys))
+                    },
+                ),
+            )
+            .with_state((keys.clone(), fetches.clone(), down.clone()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/.well-known/jwks.json",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        Self {
+            url,
+            keys,
+            fetches,
+            down,
+        }
+    }
+
+    // Publishes new keys, in place of the old ones
+    fn rotate(&self, keys: Vec<Jwk>) {
+        *self.keys.lock().unwrap() = JwkSet { keys };
+    }
+
+    // Answers every fetch with a 503 from now on
+    fn go_down(&self) {
+        self.down.store(true, Ordering::Relaxed);
+    }
+
+    fn fetches(&self) -> usize {
+        self.fetches.load(Ordering::Relaxed)
+    }
+}
+
+#[tokio::test]
+async fn valid_tokens_reach_the_handler() {
+    TestApp::with_args(["--auth-secret", SECRET])
+        .get("/v2/me")
+        .bearer(&hs256(&claims(json!({}))))
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "id": "alice" }));
+}
+
+#[tokio::test]
+async fn requests_without_a_token_get_a_401() {
+    TestApp::with_args(["--auth-secret", SECRET])
+        .get("/v2/me")
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED)
+        .assert_header("www-authenticate", "Bearer");
+}
+
+#[tokio::test]
+async fn invalid_tokens_get_a_401() {
+    let app = TestApp::with_args([
+        "--auth-secret",
+        SECRET,
+        "--auth-issuer",
+        "https://issuer.example.com",
+        "--auth-audience",
+        "rust-starter",
+    ]);
+    let valid = json!({ "iss": "https://issuer.example.com", "aud": "rust-starter" });
+    let with = |extra: Value| {
+        let mut claims = claims(valid.clone());
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        claims
+    };
+
+    app.get("/v2/me")
+        .bearer(&hs256(&with(json!({}))))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+
+    let forged = jsonwebtoken::encode(
+        &Header::new(Algorithm::HS256),
+        &with(json!({})),
+        &EncodingKey::from_secret(b"a-different-secret-of-at-least-32-bytes"),
+    )
+    .unwrap();
+    for (token, detail) in [
+        (
+            hs256(&with(json!({ "exp": now() - 120 }))),
+            "the token has expired",
+        ),
+        (
+            hs256(&with(json!({ "iss": "https://evil.example.com" }))),
+            "the token has the wrong issuer",
+        ),
+        (
+            hs256(&with(json!({ "aud": "another-api" }))),
+            "the token has the wrong audience",
+        ),
+        (forged, "the token's signature is invalid"),
+        ("not-a-jwt".to_owned(), "the token is malformed"),
+    ] {
+        app.get("/v2/me")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_problem(StatusCode::UNAUTHORIZED)
+            .assert_header("www-authenticate", r#"Bearer error="invalid_token""#)
+            .assert_json_includes(json!({ "detail": detail }));
+    }
+}
+
+#[tokio::test]
+async fn expiry_allows_for_clock_skew() {
+    let app = TestApp::with_args(["--auth-secret", SECRET, "--auth-leeway", "60s"]);
+
+    app.get("/v2/me")
+        .bearer(&hs256(&claims(json!({ "exp": now() - 30 }))))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    app.get("/v2/me")
+        .bearer(&hs256(&claims(json!({ "nbf": now() + 30 }))))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    app.get("/v2/me")
+        .bearer(&hs256(&claims(json!({ "nbf": now() + 120 }))))
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn public_key_tokens_are_checked_against_the_cached_jwks() {
+    let issuer = Issuer::start(vec![
+        jwk(&rsa_key(), Algorithm::RS256, "rsa"),
+        jwk(&ed25519_key(), Algorithm::EdDSA, "ed25519"),
+    ])
+    .await;
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url]);
+
+    for token in [
+        sign(&rsa_key(), Algorithm::RS256, "rsa", &claims(json!({}))),
+        sign(
+            &ed25519_key(),
+            Algorithm::EdDSA,
+            "ed25519",
+            &claims(json!({})),
+        ),
+    ] {
+        for _ in 0..2 {
+            app.get("/v2/me")
+                .bearer(&token)
+                .send()
+                .await
+                .assert_status(StatusCode::OK)
+                .assert_json(json!({ "id": "alice" }));
+        }
+    }
+    assert_eq!(issuer.fetches(), 1);
+
+    // A key that doesn't match its algorithm in the JWKS is refused
+    app.get("/v2/me")
+        .bearer(&sign(
+            &ed25519_key(),
+            Algorithm::EdDSA,
+            "rsa",
+            &claims(json!({})),
+        ))
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn rotated_keys_are_fetched() {
+    let issuer = Issuer::start(vec![jwk(&rsa_key(), Algorithm::RS256, "old")]).await;
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url]);
+    let token = |kid: &str| sign(&rsa_key(), Algorithm::RS256, kid, &claims(json!({})));
+    app.get("/v2/me")
+        .bearer(&token("old"))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+
+    // The issuer rotates its key after we fetched the JWKS
+    issuer.rotate(vec![jwk(&rsa_key(), Algorithm::RS256, "new")]);
+    app.get("/v2/me")
+        .bearer(&token("new"))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    assert_eq!(issuer.fetches(), 2);
+
+    // Right after a fetch, unknown keys don't fetch again, or every bad token would
+    app.get("/v2/me")
+        .bearer(&token("unknown"))
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED)
+        .assert_json_includes(json!({ "detail": "the token was signed with an unknown key" }));
+    assert_eq!(issuer.fetches(), 2);
+}
+
+#[tokio::test]
+async fn the_jwks_is_kept_without_the_shared_cache() {
+    let issuer = Issuer::start(vec![jwk(&rsa_key(), Algorithm::RS256, "rsa")]).await;
+    // A cache that can't hold anything
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url, "--cache-max-entries", "0"]);
+    let token = sign(&rsa_key(), Algorithm::RS256, "rsa", &claims(json!({})));
+
+    for _ in 0..3 {
+        app.get("/v2/me")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+    assert_eq!(issuer.fetches(), 1);
+}
+
+#[tokio::test]
+async fn concurrent_requests_fetch_the_jwks_once() {
+    let issuer = Issuer::start(vec![jwk(&rsa_key(), Algorithm::RS256, "rsa")]).await;
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url]);
+    let token = sign(&rsa_key(), Algorithm::RS256, "rsa", &claims(json!({})));
+
+    let responses =
+        futures::future::join_all((0..10).map(|_| app.get("/v2/me").bearer(&token).send())).await;
+
+    for response in responses {
+        response.assert_status(StatusCode::OK);
+    }
+    assert_eq!(issuer.fetches(), 1);
+}
+
+#[tokio::test]
+async fn requests_share_a_failed_fetch_and_back_off() {
+    let issuer = Issuer::start(vec![jwk(&rsa_key(), Algorithm::RS256, "rsa")]).await;
+    issuer.go_down();
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url]);
+    let token = sign(&rsa_key(), Algorithm::RS256, "rsa", &claims(json!({})));
+
+    let responses =
+        futures::future::join_all((0..10).map(|_| app.get("/v2/me").bearer(&token).send())).await;
+    for response in responses {
+        response.assert_problem(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    assert_eq!(issuer.fetches(), 1);
+
+    // Right after a failed fetch, we don't try again
+    app.get("/v2/me")
+        .bearer(&token)
+        .send()
+        .await
+        .assert_problem(StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(issuer.fetches(), 1);
+}
+
+#[tokio::test]
+async fn the_last_jwks_is_used_while_the_issuer_is_down() {
+    let issuer = Issuer::start(vec![jwk(&rsa_key(), Algorithm::RS256, "rsa")]).await;
+    // Every set expires right away, so each request fetches it again
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url, "--auth-jwks-ttl", "0s"]);
+    let token = sign(&rsa_key(), Algorithm::RS256, "rsa", &claims(json!({})));
+    app.get("/v2/me")
+        .bearer(&token)
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+
+    issuer.go_down();
+    for _ in 0..2 {
+        app.get("/v2/me")
+            .bearer(&token)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+    // The second request was within the backoff
+    assert_eq!(issuer.fetches(), 2);
+}
+
+#[tokio::test]
+async fn tokens_are_checked_once_per_request() {
+    let issuer = Issuer::start(vec![jwk(&rsa_key(), Algorithm::RS256, "rsa")]).await;
+    // Every check would fetch the JWKS again, so the fetches count the checks
+    let app = TestApp::with_args(["--auth-jwks-url", &issuer.url, "--auth-jwks-ttl", "0s"]);
+
+    app.get("/v2/me")
+        .bearer(&sign(
+            &rsa_key(),
+            Algorithm::RS256,
+            "rsa",
+            &claims(json!({})),
+        ))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    assert_eq!(issuer.fetches(), 1);
+
+    // Rejected tokens too
+    let expired = claims(json!({ "exp": now() - 3600 }));
+    app.get("/v2/me")
+        .bearer(&sign(&rsa_key(), Algorithm::RS256, "rsa", &expired))
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED);
+    assert_eq!(issuer.fetches(), 2);
+}
+
+#[tokio::test]
+async fn only_configured_algorithms_are_accepted() {
+    TestApp::with_args(["--auth-secret", SECRET, "--auth-algorithms", "RS256"])
+        .get("/v2/me")
+        .bearer(&hs256(&claims(json!({}))))
+        .send()
+        .await
+        .assert_problem(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn protected_routes_are_unavailable_without_keys() {
+    TestApp::new()
+        .get("/v2/me")
+        .bearer(&hs256(&claims(json!({}))))
+        .send()
+        .await
+        .assert_problem(StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn users_are_rate_limited_separately() {
+    let app = TestApp::with_args([
+        "--auth-secret",
+        SECRET,
+        "--rate-limit-key",
+        "user",
+        "--rate-limit-default",
+        "1/1m",
+    ]);
+    let token = |user: &str| hs256(&claims(json!({ "sub": user })));
+
+    for user in ["alice", "bob"] {
+        app.get("/v2/complex")
+            .bearer(&token(user))
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+    app.get("/v2/complex")
+        .bearer(&token("alice"))
+        .send()
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn invalid_settings_are_rejected() {
+    for (args, field) in [
+        (["--auth-secret", "too-short"], "auth.secret"),
+        (["--auth-algorithms", "none"], "auth.algorithms"),
+        (["--auth-leeway", "a while"], "auth.leeway"),
+    ] {
+        let error = Config::from_args(args).unwrap_err();
+        assert!(error.to_string().contains(field), "{error}");
+    }
+}
@@ -0,0 +1,190 @@
+// Response compression and request decompression
+
+mod support;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    routing::{get, post},
+    Router,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rust_starter::{compression, config::Config};
+use std::io::{Read, Write};
+use support::TestApp;
+use tower::ServiceExt;
+
+#[tokio::test]
+async fn large_responses_are_compressed() {
+    let response = TestApp::new()
+        .get("/openapi.json")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await;
+
+    response
+        .assert_status(StatusCode::OK)
+        .assert_header("content-encoding", "gzip")
+        .assert_header("vary", "accept-encoding");
+    let mut document = String::new();
+    GzDecoder::new(&response.body[..])
+        .read_to_string(&mut document)
+        .unwrap();
+    assert!(document.contains("\"openapi\":\"3.1.0\""));
+}
+
+#[tokio::test]
+async fn the_preferred_encoding_is_negotiated() {
+    let app = TestApp::new();
+
+    for encoding in ["br", "zstd"] {
+        app.get("/openapi.json")
+            .header("accept-encoding", format!("gzip;q=0.5, {encoding}"))
+            .send()
+            .await
+            .assert_header("content-encoding", encoding);
+    }
+}
+
+#[tokio::test]
+async fn responses_are_not_compressed_unless_accepted() {
+    let response = TestApp::new().get("/openapi.json").send().await;
+
+    assert_eq!(response.header("content-encoding"), None);
+}
+
+#[tokio::test]
+async fn small_responses_are_not_compressed() {
+    let response = TestApp::new()
+        .get("/v2/complex")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await;
+
+    assert_eq!(response.header("content-encoding"), None);
+}
+
+#[tokio::test]
+async fn the_minimum_size_is_configurable() {
+    TestApp::with_args(["--compression-min-size", "0"])
+        .get("/v2/complex")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await
+        .assert_header("content-encoding", "gzip");
+}
+
+#[tokio::test]
+async fn only_allowed_content_types_are_compressed() {
+    let app = TestApp::with_args(["--compression-content-types", "text/*"]);
+
+    let json = app
+        .get("/openapi.json")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await;
+    assert_eq!(json.header("content-encoding"), None);
+    app.get("/docs")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await
+        .assert_header("content-encoding", "gzip");
+}
+
+#[tokio::test]
+async fn streams_are_not_compressed() {
+    let config = Config::from_args([
+        "--compression-min-size",
+        "0",
+        "--compression-content-types",
+        "text/*,application/*",
+    ])
+    .unwrap();
+
+    for content_type in ["text/event-stream", "application/grpc"] {
+        let app = compression::install(
+            Router::new().route(
+                "/stream",
+                get(move || async move {
+                    (
+                        [(header::CONTENT_TYPE, content_type)],
+                        "data: Hello, World!\n\n",
+                    )
+                }),
+            ),
+            &config,
+        );
+        let request = Request::get("/stream")
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_ENCODING),
+            None,
+            "{content_type}"
+        );
+    }
+}
+
+// No route reads a request body yet, so decompression is tested on a small router with the same layers
+
+async fn echo(args: &[&str], encoding: &str, body: Vec<u8>) -> (StatusCode, String) {
+    let config = Config::from_args(args.iter().copied()).unwrap();
+    let app = compression::install(
+        Router::new().route("/echo", post(|body: String| async { body })),
+        &config,
+    );
+    let request = Request::post("/echo")
+        .header("content-encoding", encoding)
+        .body(Body::from(body))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[tokio::test]
+async fn compressed_request_bodies_are_decompressed() {
+    let (status, body) = echo(&[], "gzip", gzip(b"Hello, World!")).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "Hello, World!");
+}
+
+#[tokio::test]
+async fn decompressed_bodies_are_capped() {
+    // 1 MiB of zeros compresses to about a kilobyte
+    let bomb = gzip(&vec![b'0'; 1024 * 1024]);
+    assert!(bomb.len() < 4096);
+
+    let (status, _) = echo(&["--decompression-max-size", "64KiB"], "gzip", bomb).await;
+
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn unsupported_encodings_are_rejected() {
+    let (status, _) = echo(&[], "compress", b"Hello, World!".to_vec()).await;
+
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn invalid_sizes_are_rejected() {
+    let error = Config::from_args(["--decompression-max-size", "2MB"]).unwrap_err();
+
+    assert!(error.to_string().contains("unknown size unit"), "{error}");
+}
@@ -0,0 +1,64 @@
+// Loading the configuration from command line flags
+
+use rust_starter::config::Config;
+use std::{collections::HashMap, time::Duration};
+
+#[test]
+fn durations_take_a_unit() {
+    for (value, expected) in [
+        ("500ms", Duration::from_millis(500)),
+        ("30", Duration::from_secs(30)),
+        ("5m", Duration::from_secs(5 * 60)),
+        ("2h", Duration::from_secs(2 * 60 * 60)),
+    ] {
+        let config = Config::from_args(["--shutdown-timeout", value]).unwrap();
+        assert_eq!(config.shutdown_timeout, expected);
+    }
+}
+
+#[test]
+fn durations_that_overflow_are_rejected() {
+    let error = Config::from_args(["--shutdown-timeout", "999999999999999999h"]).unwrap_err();
+
+    assert!(!error.is_help());
+    assert!(
+        error.to_string().contains("duration is too long"),
+        "{error}"
+    );
+}
+
+#[test]
+fn rust_log_is_the_default_log_filter() {
+    let env = |value: &str| HashMap::from([("RUST_LOG".to_owned(), value.to_owned())]);
+
+    let config = Config::from_vars(env("warn,rust_starter=debug"), Vec::<String>::new()).unwrap();
+    assert_eq!(config.log_filter, "warn,rust_starter=debug");
+
+    // An invalid one is reported like any other setting, rather than failing when logging starts
+    let error = Config::from_vars(env("info,foo=nonsense"), Vec::<String>::new()).unwrap_err();
+    assert!(error.to_string().contains("RUST_LOG"), "{error}");
+
+    // Unless `log.filter` takes its place
+    let config = Config::from_vars(env("info,foo=nonsense"), ["--log-filter", "info"]).unwrap();
+    assert_eq!(config.log_filter, "info");
+}
+
+#[test]
+fn intervals_cant_be_zero() {
+    let error = Config::from_args(["--tls-reload-interval", "0s"]).unwrap_err();
+
+    assert!(error.to_string().contains("tls.reload_interval"), "{error}");
+    assert!(error.to_string().contains("more than zero"), "{error}");
+}
+
+#[test]
+fn help_lists_every_setting() {
+    let error = Config::from_args(["--help"]).unwrap_err();
+
+    assert!(error.is_help());
+    assert!(error.problems.is_empty());
+    let help = error.to_string();
+    assert!(help.contains("--shutdown-timeout"), "{help}");
+    assert!(help.contains("LOG_FORMAT"), "{help}");
+    assert!(!help.contains("missing a value"), "{help}");
+}
@@ -0,0 +1,171 @@
+// CORS headers and preflight requests
+
+mod support;
+
+use axum::http::{Method, StatusCode};
+use rust_starter::config::Config;
+use support::TestApp;
+
+fn app() -> TestApp {
+    TestApp::with_args([
+        "--cors-allowed-origins",
+        "https://app.example.com,https://*.example.org",
+        "--cors-allow-credentials",
+        "true",
+        "--cors-max-age",
+        "1h",
+    ])
+}
+
+#[tokio::test]
+async fn allowed_origins_can_read_responses() {
+    app()
+        .get("/v2/complex")
+        .header("origin", "https://app.example.com")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("access-control-allow-origin", "https://app.example.com")
+        .assert_header("access-control-allow-credentials", "true")
+        .assert_header("access-control-expose-headers", "x-request-id");
+}
+
+#[tokio::test]
+async fn other_origins_get_no_cors_headers() {
+    let response = app()
+        .get("/v2/complex")
+        .header("origin", "https://evil.example.com")
+        .send()
+        .await;
+
+    response.assert_status(StatusCode::OK);
+    assert_eq!(response.header("access-control-allow-origin"), None);
+    assert_eq!(response.header("access-control-allow-credentials"), None);
+}
+
+#[tokio::test]
+async fn wildcards_match_any_subdomain() {
+    let app = app();
+
+    for origin in ["https://a.example.org", "https://a.b.example.org"] {
+        app.get("/v2/complex")
+            .header("origin", origin)
+            .send()
+            .await
+            .assert_header("access-control-allow-origin", origin);
+    }
+    for origin in [
+        "https://example.org",
+        "http://a.example.org",
+        "https://a.example.org.evil.com",
+        "https://evilexample.org",
+    ] {
+        let response = app.get("/v2/complex").header("origin", origin).send().await;
+        assert_eq!(
+            response.header("access-control-allow-origin"),
+            None,
+            "{origin}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn preflights_are_answered() {
+    let response = app()
+        .request(Method::OPTIONS, "/v2/complex")
+        .header("origin", "https://app.example.com")
+        .header("access-control-request-method", "GET")
+        .header("access-control-request-headers", "authorization")
+        .send()
+        .await;
+
+    response
+        .assert_status(StatusCode::OK)
+        .assert_header("access-control-allow-origin", "https://app.example.com")
+        .assert_header(
+            "access-control-allow-methods",
+            "GET,HEAD,POST,PUT,PATCH,DELETE",
+        )
+        .assert_header("access-control-allow-headers", "authorization,content-type")
+        .assert_header("access-control-max-age", "3600");
+    assert!(response.body.is_empty());
+}
+
+#[tokio::test]
+async fn public_paths_allow_any_origin_without_credentials() {
+    let response = app()
+        .get("/openapi.json")
+        .header("origin", "https://anyone.example.net")
+        .send()
+        .await;
+
+    response.assert_header("access-control-allow-origin", "https://anyone.example.net");
+    assert_eq!(response.header("access-control-allow-credentials"), None);
+}
+
+#[tokio::test]
+async fn private_paths_allow_no_origin() {
+    let response = TestApp::with_args(["--cors-allowed-origins", "*"])
+        .get("/metrics")
+        .header("origin", "https://app.example.com")
+        .send()
+        .await;
+
+    response.assert_status(StatusCode::OK);
+    assert_eq!(response.header("access-control-allow-origin"), None);
+}
+
+#[tokio::test]
+async fn path_overrides_are_configurable() {
+    let app = TestApp::with_args(["--cors-public-paths", "/v2", "--cors-private-paths", "/v1"]);
+
+    app.get("/v2/complex")
+        .header("origin", "https://anyone.example.net")
+        .send()
+        .await
+        .assert_header("access-control-allow-origin", "https://anyone.example.net");
+    // The defaults are replaced, so `/openapi.json` is no longer public
+    for path in ["/v1/complex", "/openapi.json"] {
+        let response = app
+            .get(path)
+            .header("origin", "https://anyone.example.net")
+            .send()
+            .await;
+        assert_eq!(
+            response.header("access-control-allow-origin"),
+            None,
+            "{path}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn credentials_cant_be_combined_with_any_origin() {
+    let error = Config::from_args([
+        "--cors-allowed-origins",
+        "*",
+        "--cors-allow-credentials",
+        "true",
+    ])
+    .unwrap_err();
+
+    assert!(
+        error.to_string().contains("cors.allow_credentials"),
+        "{error}"
+    );
+}
+
+#[tokio::test]
+async fn invalid_origins_are_rejected() {
+    for origin in [
+        "example.com",
+        "https://*example.com",
+        "https://a.*.example.com",
+    ] {
+        let error = Config::from_args(["--cors-allowed-origins", origin]).unwrap_err();
+        assert!(
+            error.to_string().contains("cors.allowed_origins"),
+            "{error}"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+// Internal error details, which only development apps send to clients
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    middleware,
+    routing::get,
+    Router,
+};
+use rust_starter::error::{self, AppError};
+use serde_json::Value;
+use tower::ServiceExt;
+
+// A router whose only route fails with an internal error
+fn router(expose: bool) -> Router {
+    Router::new()
+        .route(
+            "/",
+            get(|| async {
+                Err::<(), AppError>(std::io::Error::other("the disk is on fire").into())
+            }),
+        )
+        .layer(middleware::from_fn_with_state(expose, error::middleware))
+}
+
+async fn detail(router: Router) -> String {
+    let response = router
+        .oneshot(Request::get("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    serde_json::from_slice::<Value>(&body).unwrap()["detail"]
+        .as_str()
+        .unwrap()
+        .to_owned()
+}
+
+#[tokio::test]
+async fn apps_side_by_side_keep_their_own_setting() {
+    let (development, production) = (router(true), router(false));
+
+    for _ in 0..10 {
+        let (shown, hidden) = tokio::join!(detail(development.clone()), detail(production.clone()));
+        assert_eq!(shown, "the disk is on fire");
+        assert_eq!(hidden, "An unexpected error occurred");
+    }
+}
+
+#[tokio::test]
+async fn details_are_hidden_outside_the_middleware() {
+    let router = Router::new().route(
+        "/",
+        get(|| async { Err::<(), AppError>(std::io::Error::other("the disk is on fire").into()) }),
+    );
+
+    assert_eq!(detail(router).await, "An unexpected error occurred");
+}
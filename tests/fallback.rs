@@ -0,0 +1,70 @@
+// Responses for requests that don't match a route
+
+mod support;
+
+use axum::http::StatusCode;
+use serde_json::json;
+use support::TestApp;
+
+#[tokio::test]
+async fn unknown_paths_get_a_problem_404() {
+    TestApp::new()
+        .get("/does/not/exist")
+        .send()
+        .await
+        .assert_problem(StatusCode::NOT_FOUND)
+        .assert_json_includes(json!({ "detail": "no route matches /does/not/exist" }));
+}
+
+#[tokio::test]
+async fn near_misses_get_suggestions() {
+    TestApp::new()
+        .get("/helthz")
+        .send()
+        .await
+        .assert_problem(StatusCode::NOT_FOUND)
+        .assert_json_includes(json!({ "suggestions": ["/healthz"] }));
+}
+
+#[tokio::test]
+async fn suggestions_ignore_case_and_trailing_slashes() {
+    TestApp::new()
+        .get("/V2/Complex/")
+        .send()
+        .await
+        .assert_json_includes(json!({ "suggestions": ["/v2/complex"] }));
+}
+
+#[tokio::test]
+async fn suggestions_can_be_turned_off() {
+    let response = TestApp::with_args(["--fallback-suggestions", "false"])
+        .get("/helthz")
+        .send()
+        .await;
+
+    response.assert_problem(StatusCode::NOT_FOUND);
+    assert!(response.json::<serde_json::Value>()["suggestions"].is_null());
+}
+
+#[tokio::test]
+async fn wrong_methods_get_a_problem_405_with_allow() {
+    TestApp::new()
+        .post("/v2/complex")
+        .send()
+        .await
+        .assert_problem(StatusCode::METHOD_NOT_ALLOWED)
+        .assert_header("allow", "GET,HEAD");
+}
+
+#[tokio::test]
+async fn unmatched_requests_are_counted() {
+    let app = TestApp::new();
+    app.get("/nope").send().await;
+    app.delete("/v2/complex").send().await;
+
+    let metrics = app.get("/metrics").send().await.text();
+    assert!(metrics.contains(r#"http_unmatched_requests_total{method="GET",reason="not_found"} 1"#));
+    assert!(metrics.contains(
+        r#"http_unmatched_requests_total{method="DELETE",reason="method_not_allowed"} 1"#
+    ));
+}
@@ -0,0 +1,185 @@
+// Request limits: URI, header and body sizes, the in-flight cap, and timeouts
+
+mod support;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{Request, StatusCode},
+    middleware,
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use futures::StreamExt;
+use rust_starter::{
+    config::Config,
+    limits::{self, Limits},
+};
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use support::TestApp;
+use tower::ServiceExt;
+
+#[tokio::test]
+async fn long_uris_get_a_414() {
+    TestApp::with_args(["--limits-max-uri-length", "32"])
+        .get(&format!("/v2/complex?{}", "a".repeat(32)))
+        .send()
+        .await
+        .assert_problem(StatusCode::URI_TOO_LONG);
+}
+
+#[tokio::test]
+async fn large_headers_get_a_431() {
+    let app = TestApp::with_args(["--limits-max-header-size", "1KiB"]);
+
+    app.get("/v2/complex")
+        .header("x-small", "a".repeat(512))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    app.get("/v2/complex")
+        .header("x-large", "a".repeat(1024))
+        .send()
+        .await
+        .assert_problem(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn bodies_declared_too_large_get_a_413() {
+    TestApp::with_args(["--limits-max-body-size", "16"])
+        .post("/v2/complex")
+        .header("content-length", "17")
+        .body("a".repeat(17))
+        .send()
+        .await
+        .assert_problem(StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+// Slow handlers and streamed bodies are tested on a small router with the same middleware
+
+fn app(args: &[&str]) -> Router {
+    let config = Config::from_args(args.iter().copied()).unwrap();
+    Router::new()
+        .route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                "Done"
+            }),
+        )
+        .route("/echo", post(|body: String| async { body }))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(Limits::new(&config)),
+            limits::middleware,
+        ))
+}
+
+async fn send(app: &Router, request: Request<Body>) -> Response {
+    app.clone().oneshot(request).await.unwrap()
+}
+
+fn get_slow() -> Request<Body> {
+    Request::get("/slow").body(Body::empty()).unwrap()
+}
+
+#[tokio::test]
+async fn streamed_bodies_stop_at_the_limit() {
+    let app = app(&["--limits-max-body-size", "16"]);
+    let chunks =
+        ["a".repeat(10), "a".repeat(10)].map(|chunk| Ok::<_, Infallible>(Bytes::from(chunk)));
+    let request = Request::post("/echo")
+        .body(Body::from_stream(futures::stream::iter(chunks)))
+        .unwrap();
+
+    assert_eq!(
+        send(&app, request).await.status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[tokio::test]
+async fn slow_handlers_get_a_503() {
+    let app = app(&["--limits-request-timeout", "50ms"]);
+
+    let response = send(&app, get_slow()).await;
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+}
+
+#[tokio::test]
+async fn slow_clients_get_a_408() {
+    let app = app(&["--limits-request-timeout", "50ms"]);
+    // The client sends part of its body, and then nothing
+    let body = futures::stream::once(async { Ok::<_, Infallible>(Bytes::from("Hello")) })
+        .chain(futures::stream::pending());
+    let request = Request::post("/echo")
+        .body(Body::from_stream(body))
+        .unwrap();
+
+    assert_eq!(
+        send(&app, request).await.status(),
+        StatusCode::REQUEST_TIMEOUT
+    );
+}
+
+#[tokio::test]
+async fn routes_can_have_their_own_timeout() {
+    let relaxed = app(&[
+        "--limits-request-timeout",
+        "50ms",
+        "--limits-route-timeouts",
+        "/slow=1s",
+    ]);
+    assert_eq!(send(&relaxed, get_slow()).await.status(), StatusCode::OK);
+
+    let unlimited = app(&[
+        "--limits-request-timeout",
+        "50ms",
+        "--limits-route-timeouts",
+        "/slow=off",
+    ]);
+    assert_eq!(send(&unlimited, get_slow()).await.status(), StatusCode::OK);
+
+    let strict = app(&["--limits-route-timeouts", "/slow=50ms"]);
+    assert_eq!(
+        send(&strict, get_slow()).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn requests_over_the_in_flight_cap_are_shed() {
+    let app = app(&["--limits-max-in-flight", "1"]);
+
+    let first = tokio::spawn({
+        let app = app.clone();
+        async move { send(&app, get_slow()).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let shed = send(&app, get_slow()).await;
+
+    assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(shed.headers()["retry-after"], "1");
+    assert_eq!(first.await.unwrap().status(), StatusCode::OK);
+    // Once the first request is done, there is room again
+    assert_eq!(send(&app, get_slow()).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn invalid_limits_are_rejected() {
+    for (args, field) in [
+        (["--limits-max-in-flight", "0"], "limits.max_in_flight"),
+        (
+            ["--limits-route-timeouts", "/v1=soon"],
+            "limits.route_timeouts",
+        ),
+        (["--limits-max-body-size", "big"], "limits.max_body_size"),
+    ] {
+        let error = Config::from_args(args).unwrap_err();
+        assert!(error.to_string().contains(field), "{error}");
+    }
+}
@@ -0,0 +1,261 @@
+// Listening sockets: dual-stack and IPv6-only TCP, Unix domain sockets, and TLS with certificate reloading
+
+mod support;
+
+use rust_starter::{
+    listener::{BindOptions, BoundListener, ListenAddr},
+    shutdown::Shutdown,
+    tls::Tls,
+};
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use rustls_pki_types::{pem::PemObject, CertificateDer};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use support::TestApp;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(FIXTURES).join(name)
+}
+
+fn plain() -> BindOptions {
+    BindOptions {
+        unix_socket_mode: 0o660,
+        tls: None,
+    }
+}
+
+// A directory of our own under the system's temp directory, for socket and certificate files
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust-starter-{}", uuid::Uuid::new_v4().simple()));
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+// Serves a fresh app on `listener` until the returned coordinator is triggered
+fn serve(listener: BoundListener) -> Shutdown {
+    let app = TestApp::new().app;
+    let shutdown = app.shutdown.clone();
+    tokio::spawn(app.serve(vec![listener]));
+    shutdown
+}
+
+fn port(listener: &BoundListener) -> u16 {
+    match listener {
+        BoundListener::Tcp(listener) => listener.local_addr().unwrap().port(),
+        BoundListener::Tls(listener) => axum::serve::Listener::local_addr(listener).unwrap().port(),
+        _ => panic!("not a TCP listener"),
+    }
+}
+
+// Makes a plain HTTP/1.1 request over `stream`, and returns the whole response
+async fn get(mut stream: impl AsyncRead + AsyncWrite + Unpin, path: &str) -> String {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn dual_stack_listeners_accept_ipv4_and_ipv6() {
+    let listener = "dual:[::]:0"
+        .parse::<ListenAddr>()
+        .unwrap()
+        .bind(&plain())
+        .unwrap();
+    let port = port(&listener);
+    let _shutdown = serve(listener);
+
+    for addr in [
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], port)),
+    ] {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(get(stream, "/healthz").await.starts_with("HTTP/1.1 200"));
+    }
+}
+
+#[tokio::test]
+async fn ipv6_listeners_only_accept_ipv6() {
+    let listener = "[::1]:0"
+        .parse::<ListenAddr>()
+        .unwrap()
+        .bind(&plain())
+        .unwrap();
+    let port = port(&listener);
+    let _shutdown = serve(listener);
+
+    let stream = TcpStream::connect(("::1", port)).await.unwrap();
+    assert!(get(stream, "/healthz").await.starts_with("HTTP/1.1 200"));
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_sockets_serve_http_and_are_removed_on_shutdown() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = temp_dir().join("app.sock");
+    // A socket left behind by a process that crashed is replaced
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let listener = ListenAddr::Unix { path: path.clone() }
+        .bind(&plain())
+        .unwrap();
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o660
+    );
+    let app = TestApp::with_args(["--shutdown-delay", "0s"]).app;
+    let shutdown = app.shutdown.clone();
+    let server = tokio::spawn(app.serve(vec![listener]));
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    assert!(get(stream, "/healthz").await.starts_with("HTTP/1.1 200"));
+
+    // While we are still listening, a second server can't take the socket over
+    assert!(ListenAddr::Unix { path: path.clone() }
+        .bind(&plain())
+        .is_err());
+
+    shutdown.trigger();
+    server.await.unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unix_sockets_refuse_to_replace_other_files() {
+    let path = temp_dir().join("app.sock");
+    fs::write(&path, "not a socket").unwrap();
+
+    assert!(ListenAddr::Unix { path: path.clone() }
+        .bind(&plain())
+        .is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+}
+
+// A client that trusts our test CA, offering `alpn` protocols
+fn connector(alpn: &[&[u8]]) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(CertificateDer::from_pem_file(fixture("tls-ca.pem")).unwrap())
+        .unwrap();
+    let mut config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn handshake(port: u16, alpn: &[&[u8]]) -> TlsStream<TcpStream> {
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    connector(alpn)
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .expect("TLS handshake failed")
+}
+
+fn peer_certificate(stream: &TlsStream<TcpStream>) -> CertificateDer<'static> {
+    stream.get_ref().1.peer_certificates().unwrap()[0]
+        .clone()
+        .into_owned()
+}
+
+fn tls_options(tls: Tls) -> BindOptions {
+    BindOptions {
+        unix_socket_mode: 0o660,
+        tls: Some(tls),
+    }
+}
+
+#[tokio::test]
+async fn tls_listeners_serve_https() {
+    let tls = Tls::load(&fixture("tls-cert.pem"), &fixture("tls-key.pem")).unwrap();
+    let listener = "127.0.0.1:0"
+        .parse::<ListenAddr>()
+        .unwrap()
+        .bind(&tls_options(tls))
+        .unwrap();
+    assert!(listener.local_addr().starts_with("https://127.0.0.1:"));
+    let port = port(&listener);
+    let _shutdown = serve(listener);
+
+    let stream = handshake(port, &[b"http/1.1"]).await;
+    assert!(get(stream, "/healthz").await.starts_with("HTTP/1.1 200"));
+
+    // Clients that speak HTTP/2 get it
+    let stream = handshake(port, &[b"h2", b"http/1.1"]).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+}
+
+#[tokio::test]
+async fn keys_that_dont_match_the_certificate_are_refused() {
+    let error = Tls::load(&fixture("tls-cert.pem"), &fixture("tls-key-renewed.pem"))
+        .err()
+        .expect("mismatched key was loaded");
+
+    assert!(error.to_string().contains("doesn't belong to"), "{error}");
+}
+
+#[tokio::test]
+async fn renewed_certificates_are_picked_up() {
+    let dir = temp_dir();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    fs::copy(fixture("tls-cert.pem"), &cert).unwrap();
+    fs::copy(fixture("tls-key.pem"), &key).unwrap();
+
+    let shutdown = Shutdown::new();
+    let tls = Tls::load(&cert, &key).unwrap();
+    tls.watch(Duration::from_millis(20), shutdown.clone());
+    let listener = "127.0.0.1:0"
+        .parse::<ListenAddr>()
+        .unwrap()
+        .bind(&tls_options(tls))
+        .unwrap();
+    let port = port(&listener);
+    let _server = serve(listener);
+
+    let original = CertificateDer::from_pem_file(fixture("tls-cert.pem")).unwrap();
+    let renewed = CertificateDer::from_pem_file(fixture("tls-cert-renewed.pem")).unwrap();
+    let open = handshake(port, &[b"http/1.1"]).await;
+    assert_eq!(peer_certificate(&open), original);
+
+    // Renewal writes the certificate and key one after the other; until both are written they don't match
+    fs::copy(fixture("tls-cert-renewed.pem"), &cert).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        peer_certificate(&handshake(port, &[b"http/1.1"]).await),
+        original
+    );
+    fs::copy(fixture("tls-key-renewed.pem"), &key).unwrap();
+
+    let mut served = original.clone();
+    for _ in 0..100 {
+        served = peer_certificate(&handshake(port, &[b"http/1.1"]).await);
+        if served == renewed {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(served, renewed);
+
+    // Connections that were already open keep working
+    assert!(get(open, "/healthz").await.starts_with("HTTP/1.1 200"));
+    shutdown.trigger();
+}
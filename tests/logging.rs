@@ -0,0 +1,69 @@
+// Log lines: the static fields every event carries, in text and JSON
+
+mod support;
+
+use rust_starter::{config::Config, logging};
+use serde_json::Value;
+use support::Logs;
+use tracing_subscriber::{layer::SubscriberExt, Registry};
+
+// Logs one event with the layer `args` configure, returning what was written
+fn log_line(args: &[&str]) -> String {
+    let config = Config::from_args(args.iter().copied()).unwrap();
+    let logs = Logs::default();
+    let subscriber = Registry::default().with(logging::layer(&config, logs.clone(), false));
+
+    tracing::subscriber::with_default(subscriber, || tracing::info!(answer = 42, "Hello"));
+    logs.contents()
+}
+
+#[test]
+fn text_lines_end_with_the_static_fields() {
+    let line = log_line(&[
+        "--log-format",
+        "compact",
+        "--log-deployment-id",
+        "d-1",
+        "--log-fields",
+        "team=payments,note=two words,query=a=b",
+    ]);
+
+    assert!(
+        line.trim_end().ends_with(
+            r#"service=rust-starter deployment_id=d-1 team=payments note="two words" query="a=b""#
+        ),
+        "{line}"
+    );
+    assert!(line.contains("Hello"), "{line}");
+}
+
+#[test]
+fn json_lines_have_the_static_fields_at_the_top_level() {
+    let line = log_line(&[
+        "--log-format",
+        "json",
+        "--log-fields",
+        "team=payments,note=\"quoted\" and spaced",
+    ]);
+
+    let event: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(event["service"], "rust-starter");
+    assert_eq!(event["team"], "payments");
+    assert_eq!(event["note"], "\"quoted\" and spaced");
+    assert_eq!(event["level"], "INFO");
+    assert_eq!(event["fields"]["message"], "Hello");
+    assert_eq!(event["fields"]["answer"], 42);
+}
+
+#[test]
+fn static_fields_cant_replace_the_ones_every_event_has() {
+    for field in [
+        "timestamp=now",
+        "level=loud",
+        "service=other",
+        "two words=x",
+    ] {
+        let error = Config::from_args(["--log-fields", field]).unwrap_err();
+        assert!(error.to_string().contains("log.fields"), "{error}");
+    }
+}
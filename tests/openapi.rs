@@ -0,0 +1,102 @@
+// The OpenAPI document and the Redoc page
+
+mod support;
+
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+use support::TestApp;
+
+#[tokio::test]
+async fn the_document_is_openapi_3_1() {
+    TestApp::new()
+        .get("/openapi.json")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json_includes(json!({ "openapi": "3.1.0", "info": { "title": "rust-starter" } }));
+}
+
+#[tokio::test]
+async fn the_document_covers_every_route() {
+    let document: Value = TestApp::new().get("/openapi.json").send().await.json();
+
+    let mut paths: Vec<&str> = document["paths"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "/",
+            "/complex",
+            "/docs",
+            "/docs/redoc.standalone.js",
+            "/healthz",
+            "/metrics",
+            "/openapi.json",
+            "/readyz",
+            "/v1",
+            "/v1/complex",
+            "/v2",
+            "/v2/complex",
+            "/v2/me"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn complex_is_described_by_its_schema() {
+    TestApp::new()
+        .get("/openapi.json")
+        .send()
+        .await
+        .assert_json_includes(json!({
+            "paths": { "/v1/complex": { "get": { "responses": { "200": { "content": { "application/json": {
+                "schema": { "$ref": "#/components/schemas/Message" }
+            } } } } } } },
+            "components": { "schemas": { "Message": { "required": ["message"] } } },
+        }));
+}
+
+#[tokio::test]
+async fn docs_render_the_document() {
+    let response = TestApp::new().get("/docs").send().await;
+
+    response
+        .assert_status(StatusCode::OK)
+        .assert_header("content-type", "text/html; charset=utf-8");
+    assert!(response.text().contains("Redoc"));
+}
+
+#[tokio::test]
+async fn docs_only_load_from_us() {
+    let app = TestApp::new();
+    let response = app.get("/docs").send().await;
+    let page = response.text();
+
+    assert!(page.contains(r#"src="/docs/redoc.standalone.js""#));
+    assert!(!page.contains("cdn."));
+    assert!(!page.contains("fonts.googleapis.com"));
+    let policy = response.header("content-security-policy").unwrap();
+    assert!(!policy.contains("https:"), "{policy}");
+    let script = app.get("/docs/redoc.standalone.js").send().await;
+    script
+        .assert_status(StatusCode::OK)
+        .assert_header("content-type", "text/javascript; charset=utf-8");
+    assert!(script.text().contains("Redoc"));
+}
+
+#[tokio::test]
+async fn docs_can_be_turned_off() {
+    let app = TestApp::with_args(["--openapi-docs", "false"]);
+
+    app.get("/docs")
+        .send()
+        .await
+        .assert_problem(StatusCode::NOT_FOUND);
+    let document: Value = app.get("/openapi.json").send().await.json();
+    assert!(document["paths"]["/docs"].is_null());
+}
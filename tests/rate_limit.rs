@@ -0,0 +1,400 @@
+// Rate limiting, with the in-memory store, and with Redis when `REDIS_URL` is set
+
+mod support;
+
+use async_trait::async_trait;
+use axum::{http::StatusCode, middleware, routing::get, Extension, Router};
+use rust_starter::{
+    config::Config,
+    rate_limit::{self, gcra, Outcome, Quota, RateLimitStore, RateLimiter, RedisStore, UserId},
+};
+use std::{
+    error::Error,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use support::TestApp;
+use tower::ServiceExt;
+
+const MINUTE: Quota = Quota {
+    limit: 3,
+    period: Duration::from_secs(60),
+};
+
+#[tokio::test]
+async fn responses_say_where_the_client_stands() {
+    TestApp::with_args(["--rate-limit-default", "3/1m"])
+        .get("/v2/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("ratelimit-limit", "3")
+        .assert_header("ratelimit-remaining", "2")
+        .assert_header("ratelimit-reset", "20")
+        .assert_header("ratelimit-policy", "3;w=60");
+}
+
+#[tokio::test]
+async fn clients_over_their_quota_get_a_429() {
+    let app = TestApp::with_args(["--rate-limit-default", "3/1m"]);
+    for _ in 0..3 {
+        app.get("/v2/complex")
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+
+    app.get("/v2/complex")
+        .send()
+        .await
+        .assert_problem(StatusCode::TOO_MANY_REQUESTS)
+        .assert_header("retry-after", "20")
+        .assert_header("ratelimit-remaining", "0")
+        .assert_header("ratelimit-reset", "60");
+}
+
+#[tokio::test]
+async fn the_bucket_refills_continuously() {
+    let mut tat = None;
+    for _ in 0..3 {
+        let (outcome, new_tat) = gcra(tat, 0, MINUTE);
+        assert!(outcome.allowed);
+        tat = new_tat;
+    }
+
+    assert!(!gcra(tat, 0, MINUTE).0.allowed);
+    assert!(!gcra(tat, 19_999_999, MINUTE).0.allowed);
+    assert!(gcra(tat, 20_000_000, MINUTE).0.allowed);
+}
+
+#[tokio::test]
+async fn quotas_that_dont_divide_evenly_allow_their_limit() {
+    // The first request leaves 699
+    TestApp::with_args(["--rate-limit-default", "700/1s"])
+        .get("/v2/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("ratelimit-limit", "700")
+        .assert_header("ratelimit-remaining", "699")
+        .assert_header("ratelimit-policy", "700;w=1");
+
+    // A burst of 700, then one every 1.428ms, rather than one a millisecond
+    let quota = "700/1s".parse::<Quota>().unwrap();
+    let mut tat = None;
+    for _ in 0..700 {
+        let (outcome, new_tat) = gcra(tat, 0, quota);
+        assert!(outcome.allowed);
+        tat = new_tat;
+    }
+    assert!(!gcra(tat, 0, quota).0.allowed);
+    assert!(!gcra(tat, 1_427, quota).0.allowed);
+    assert!(gcra(tat, 1_428, quota).0.allowed);
+}
+
+#[tokio::test]
+async fn clients_are_limited_separately_by_ip() {
+    let app = TestApp::with_args(["--rate-limit-default", "1/1m"]);
+
+    for client in ["203.0.113.1:1234", "203.0.113.2:1234"] {
+        app.get("/v2/complex")
+            .peer(client)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+    // Another connection from the same address
+    app.get("/v2/complex")
+        .peer("203.0.113.1:5678")
+        .send()
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn forwarded_addresses_are_ignored_without_trusted_proxies() {
+    let server = TestApp::with_args(["--rate-limit-default", "1/1m"])
+        .serve()
+        .await;
+
+    server
+        .get("/v2/complex")
+        .header("x-forwarded-for", "203.0.113.1")
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    // Made up addresses don't get a client a new quota
+    server
+        .get("/v2/complex")
+        .header("x-forwarded-for", "203.0.113.2")
+        .send()
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn clients_behind_trusted_proxies_are_told_apart() {
+    let app = TestApp::with_args([
+        "--rate-limit-default",
+        "1/1m",
+        "--rate-limit-trusted-proxies",
+        "10.0.0.0/8,192.0.2.1",
+    ]);
+    let send = |peer: &str, forwarded_for: &str| {
+        app.get("/v2/complex")
+            .peer(peer)
+            .header("x-forwarded-for", forwarded_for)
+            .send()
+    };
+
+    for client in ["203.0.113.1", "203.0.113.2"] {
+        send("10.0.0.1:1234", client)
+            .await
+            .assert_status(StatusCode::OK);
+    }
+    // The same client, through two of our proxies
+    send("192.0.2.1:1234", "203.0.113.1, 10.0.0.2")
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+    // Hops before the client's are whatever it sent, so they don't count
+    send("10.0.0.1:1234", "198.51.100.7, 203.0.113.2")
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+    // And clients that aren't our proxies can't claim to be someone else
+    send("198.51.100.1:1234", "203.0.113.3")
+        .await
+        .assert_status(StatusCode::OK);
+    send("198.51.100.1:1234", "203.0.113.4")
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn clients_can_be_limited_by_api_key() {
+    let app = TestApp::with_args([
+        "--rate-limit-default",
+        "1/1m",
+        "--rate-limit-key",
+        "api_key",
+    ]);
+
+    for key in ["first", "second"] {
+        app.get("/v2/complex")
+            .header("x-api-key", key)
+            .send()
+            .await
+            .assert_status(StatusCode::OK);
+    }
+    app.get("/v2/complex")
+        .header("x-api-key", "first")
+        .send()
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+}
+
+// A store that remembers the keys it was asked about, and allows every request
+#[derive(Default)]
+struct Keys(Mutex<Vec<String>>);
+
+#[async_trait]
+impl RateLimitStore for Keys {
+    async fn acquire(
+        &self,
+        key: &str,
+        _quota: Quota,
+    ) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+        self.0.lock().unwrap().push(key.to_owned());
+        Ok(Outcome {
+            allowed: true,
+            backlog_us: 0,
+        })
+    }
+}
+
+#[tokio::test]
+async fn api_keys_are_stored_as_their_sha256() {
+    let config = Config::from_args([
+        "--rate-limit-default",
+        "1/1m",
+        "--rate-limit-key",
+        "api_key",
+    ])
+    .unwrap();
+    let keys = Arc::new(Keys::default());
+    let limiter = RateLimiter {
+        store: keys.clone(),
+        ..RateLimiter::new(&config).unwrap()
+    };
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .layer(middleware::from_fn_with_state(
+            limiter,
+            rate_limit::middleware,
+        ));
+
+    let request = axum::http::Request::get("/")
+        .header("x-api-key", "first")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    app.oneshot(request).await.unwrap();
+
+    // The same in every replica and every release, unlike Rust's default hasher
+    assert_eq!(
+        *keys.0.lock().unwrap(),
+        ["rate_limit:*:key:a7937b64b8caa58f03721bb6bacf5c78cb235febe0e70b1b84cd99541461a08e"]
+    );
+}
+
+#[tokio::test]
+async fn clients_can_be_limited_by_user() {
+    let config =
+        Config::from_args(["--rate-limit-default", "1/1m", "--rate-limit-key", "user"]).unwrap();
+    let limiter = RateLimiter::new(&config).unwrap();
+    // Authentication middleware would insert the user, this inserts a fixed one
+    let app = |user: &str| {
+        Router::new()
+            .route("/", get(|| async { "Hello, World!" }))
+            .layer(middleware::from_fn_with_state(
+                limiter.clone(),
+                rate_limit::middleware,
+            ))
+            .layer(Extension(UserId(user.to_owned())))
+    };
+    let get = |app: Router| async move {
+        app.oneshot(
+            axum::http::Request::get("/")
+                .body(axum::body::Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .status()
+    };
+
+    assert_eq!(get(app("alice")).await, StatusCode::OK);
+    assert_eq!(get(app("bob")).await, StatusCode::OK);
+    assert_eq!(get(app("alice")).await, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn routes_can_have_their_own_quota() {
+    let app = TestApp::with_args([
+        "--rate-limit-default",
+        "1/1m",
+        "--rate-limit-routes",
+        "/v1=2/1m,/healthz=off",
+    ]);
+
+    app.get("/v1/complex")
+        .send()
+        .await
+        .assert_header("ratelimit-limit", "2");
+    app.get("/v1/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+    // `/v2` counts against the default quota, separately from `/v1`
+    app.get("/v2/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("ratelimit-limit", "1");
+    app.get("/v1/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::TOO_MANY_REQUESTS);
+
+    for _ in 0..3 {
+        let response = app.get("/healthz").send().await;
+        response.assert_status(StatusCode::OK);
+        assert_eq!(response.header("ratelimit-limit"), None);
+    }
+}
+
+#[tokio::test]
+async fn probes_and_metrics_are_not_limited_by_default() {
+    let app = TestApp::with_args(["--rate-limit-default", "1/1m"]);
+
+    for path in ["/healthz", "/readyz", "/metrics"] {
+        for _ in 0..2 {
+            app.get(path).send().await.assert_status(StatusCode::OK);
+        }
+    }
+}
+
+#[tokio::test]
+async fn requests_are_let_through_when_the_store_fails() {
+    let app = TestApp::with_args([
+        "--rate-limit-default",
+        "1/1m",
+        "--rate-limit-backend",
+        "redis",
+        "--rate-limit-redis-url",
+        "redis://127.0.0.1:1",
+    ]);
+
+    for _ in 0..2 {
+        let response = app.get("/v2/complex").send().await;
+        response.assert_status(StatusCode::OK);
+        assert_eq!(response.header("ratelimit-limit"), None);
+    }
+}
+
+#[tokio::test]
+async fn invalid_settings_are_rejected() {
+    for (args, field) in [
+        (["--rate-limit-default", "lots"], "rate_limit.default"),
+        (["--rate-limit-routes", "v1=10/s"], "rate_limit.routes"),
+        (["--rate-limit-backend", "redis"], "rate_limit.redis_url"),
+        // More than one request per millisecond
+        (["--rate-limit-default", "2000/1s"], "rate_limit.default"),
+        (
+            ["--rate-limit-trusted-proxies", "10.0.0.0/33"],
+            "rate_limit.trusted_proxies",
+        ),
+    ] {
+        let error = Config::from_args(args).unwrap_err();
+        assert!(error.to_string().contains(field), "{error}");
+    }
+}
+
+// Needs a Redis server, like `docker run -p 6379:6379 redis` with `REDIS_URL=redis://localhost:6379`
+#[tokio::test]
+async fn redis_shares_limits_between_replicas() {
+    let Ok(url) = std::env::var("REDIS_URL") else {
+        eprintln!("REDIS_URL isn't set, skipping");
+        return;
+    };
+    // Every run uses its own key, so runs don't see each other's counts
+    let key = format!("rate_limit:test:{}", uuid::Uuid::now_v7());
+    let replicas = [
+        RedisStore::new(&url).unwrap(),
+        RedisStore::new(&url).unwrap(),
+    ];
+
+    for replica in replicas.iter().cycle().take(3) {
+        assert!(replica.acquire(&key, MINUTE).await.unwrap().allowed);
+    }
+    let outcome = replicas[1].acquire(&key, MINUTE).await.unwrap();
+    assert!(!outcome.allowed);
+    assert!(outcome.backlog_us > 59_000_000 && outcome.backlog_us <= 60_000_000);
+
+    // And through the whole app
+    let client =
+        SocketAddr::from((Ipv6Addr::from(uuid::Uuid::now_v7().as_u128()), 1234)).to_string();
+    let app = TestApp::with_args([
+        "--rate-limit-default",
+        "1/1m",
+        "--rate-limit-backend",
+        "redis",
+        "--rate-limit-redis-url",
+        &url,
+    ]);
+    let send = || app.get("/v2/complex").peer(&client).send();
+    send().await.assert_status(StatusCode::OK);
+    send()
+        .await
+        .assert_problem(StatusCode::TOO_MANY_REQUESTS)
+        .assert_header("retry-after", "60");
+}
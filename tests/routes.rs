@@ -0,0 +1,72 @@
+// The example routes, and the request id every response carries
+
+mod support;
+
+use axum::http::StatusCode;
+use serde_json::json;
+use support::TestApp;
+
+#[tokio::test]
+async fn root_says_hello() {
+    let response = TestApp::new().get("/").send().await;
+
+    response.assert_status(StatusCode::OK);
+    assert_eq!(response.text(), "Hello, World!");
+}
+
+#[tokio::test]
+async fn complex_returns_json() {
+    TestApp::new()
+        .get("/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("content-type", "application/json")
+        .assert_json(json!({ "message": "Hello, World!" }));
+}
+
+#[tokio::test]
+async fn every_response_gets_a_request_id() {
+    let response = TestApp::new().get("/").send().await;
+
+    let id = response.header("x-request-id").expect("no request id");
+    assert!(uuid_like(id), "{id} isn't a UUID");
+}
+
+#[tokio::test]
+async fn a_valid_incoming_request_id_is_kept() {
+    TestApp::new()
+        .get("/")
+        .header("x-request-id", "from-the-proxy")
+        .send()
+        .await
+        .assert_header("x-request-id", "from-the-proxy");
+}
+
+#[tokio::test]
+async fn an_invalid_incoming_request_id_is_replaced() {
+    let response = TestApp::new()
+        .get("/")
+        .header("x-request-id", "x".repeat(200))
+        .send()
+        .await;
+
+    assert!(uuid_like(response.header("x-request-id").unwrap()));
+}
+
+#[tokio::test]
+async fn errors_include_the_request_id() {
+    let response = TestApp::new()
+        .get("/nope")
+        .header("x-request-id", "abc-123")
+        .send()
+        .await;
+
+    response
+        .assert_problem(StatusCode::NOT_FOUND)
+        .assert_json_includes(json!({ "request_id": "abc-123" }));
+}
+
+fn uuid_like(id: &str) -> bool {
+    id.len() == 36 && id.chars().filter(|c| *c == '-').count() == 4
+}
@@ -0,0 +1,27 @@
+// The whole server, on a listener we bound ourselves
+// `run` installs the global tracing subscriber, which can only happen once per process, so it gets a test binary of its own
+
+use rust_starter::{config::Config, listener::BoundListener, tls};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn run_serves_on_the_given_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // `listen` would fail to bind if it were used, since the port is taken
+    let config = Config::from_args(["--listen", &addr.to_string()]).unwrap();
+
+    // Our HTTP client needs a crypto provider before `run` gets to install one
+    tls::install_crypto_provider();
+    let server = tokio::spawn(rust_starter::run(config, BoundListener::Tcp(listener)));
+
+    let body = reqwest::get(format!("http://{addr}/"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(body, "Hello, World!");
+    assert!(!server.is_finished());
+    server.abort();
+}
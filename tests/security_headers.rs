@@ -0,0 +1,123 @@
+// Security headers, and the Content-Security-Policy nonce
+
+mod support;
+
+use axum::http::StatusCode;
+use rust_starter::{
+    config::Config,
+    security_headers::{ContentSecurityPolicy, Source},
+};
+use support::TestApp;
+
+#[tokio::test]
+async fn responses_get_security_headers() {
+    let app = TestApp::new();
+
+    for (path, status) in [
+        ("/v2/complex", StatusCode::OK),
+        ("/missing", StatusCode::NOT_FOUND),
+    ] {
+        app.get(path)
+            .send()
+            .await
+            .assert_status(status)
+            .assert_header(
+                "strict-transport-security",
+                "max-age=31536000; includeSubDomains",
+            )
+            .assert_header("x-content-type-options", "nosniff")
+            .assert_header("referrer-policy", "no-referrer")
+            .assert_header(
+                "permissions-policy",
+                "camera=(), geolocation=(), microphone=(), payment=(), usb=()",
+            )
+            .assert_header("x-frame-options", "DENY")
+            .assert_header(
+                "content-security-policy",
+                "default-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'",
+            );
+    }
+}
+
+#[tokio::test]
+async fn headers_can_be_changed_or_turned_off() {
+    let response = TestApp::with_args([
+        "--security-headers-hsts",
+        "off",
+        "--security-headers-frame-options",
+        "SAMEORIGIN",
+        "--security-headers-content-security-policy",
+        "default-src 'self'; script-src 'self' https://cdn.example.com",
+    ])
+    .get("/v2/complex")
+    .send()
+    .await;
+
+    assert_eq!(response.header("strict-transport-security"), None);
+    response
+        .assert_header("x-frame-options", "SAMEORIGIN")
+        .assert_header(
+            "content-security-policy",
+            "default-src 'self'; script-src 'self' https://cdn.example.com",
+        );
+}
+
+#[tokio::test]
+async fn docs_scripts_carry_the_request_nonce() {
+    let app = TestApp::new();
+    let mut nonces = Vec::new();
+
+    for _ in 0..2 {
+        let response = app.get("/docs").send().await;
+        let policy = response.header("content-security-policy").unwrap();
+        let nonce = policy
+            .split("'nonce-")
+            .nth(1)
+            .and_then(|rest| rest.split('\'').next())
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            response
+                .text()
+                .matches(&format!("nonce=\"{nonce}\""))
+                .count(),
+            2
+        );
+        nonces.push(nonce);
+    }
+    assert_ne!(nonces[0], nonces[1]);
+}
+
+#[tokio::test]
+async fn policies_are_written_like_the_header() {
+    let policy: ContentSecurityPolicy = "script-src 'nonce' 'strict-dynamic'; img-src 'self' data:"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        policy,
+        ContentSecurityPolicy::new()
+            .script_src([Source::Nonce, Source::StrictDynamic])
+            .img_src([Source::SelfOrigin, Source::Scheme("data:".into())])
+    );
+}
+
+#[tokio::test]
+async fn invalid_headers_are_rejected() {
+    for (args, field) in [
+        (
+            [
+                "--security-headers-content-security-policy",
+                "script-src 'unsafe-everything'",
+            ],
+            "security_headers.content_security_policy",
+        ),
+        (
+            ["--security-headers-referrer-policy", "no\u{1}referrer"],
+            "security_headers.referrer_policy",
+        ),
+    ] {
+        let error = Config::from_args(args).unwrap_err();
+        assert!(error.to_string().contains(field), "{error}");
+    }
+}
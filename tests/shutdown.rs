@@ -0,0 +1,130 @@
+// Graceful shutdown: failing readiness checks, draining in-flight requests, and abandoning those that take too long
+// A token checked against a slow JWKS endpoint keeps a request in flight for as long as we need
+
+mod support;
+
+use axum::{http::StatusCode, routing::get, Json, Router};
+use jsonwebtoken::{
+    jwk::{Jwk, JwkSet},
+    Algorithm, EncodingKey, Header,
+};
+use serde_json::json;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use support::{capture_logs, TestApp};
+use tokio::net::TcpListener;
+
+const RSA_KEY: &str = include_str!("fixtures/jwt-rsa.pem");
+
+fn key() -> EncodingKey {
+    EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap()
+}
+
+// An issuer whose JWKS takes `delay` to arrive, returning its URL
+async fn slow_issuer(delay: Duration) -> String {
+    let mut jwk = Jwk::from_encoding_key(&key(), Algorithm::RS256).unwrap();
+    jwk.common.key_id = Some("rsa".into());
+    let jwks = JwkSet { keys: vec![jwk] };
+    let router = Router::new().route(
+        "/jwks.json",
+        get(move || async move {
+            tokio::time::sleep(delay).await;
+            Json(jwks)
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/jwks.json", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    url
+}
+
+fn token() -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("rsa".into());
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+    jsonwebtoken::encode(&header, &json!({ "sub": "alice", "exp": exp }), &key()).unwrap()
+}
+
+#[tokio::test]
+async fn connections_are_accepted_until_the_delay_has_passed() {
+    let server = TestApp::with_args(["--shutdown-delay", "300ms"])
+        .serve()
+        .await;
+    let started = Instant::now();
+
+    server.trigger_shutdown();
+    // A new connection, which the platform may still send us before it sees that we aren't ready
+    server
+        .get("/readyz")
+        .send()
+        .await
+        .assert_status(StatusCode::SERVICE_UNAVAILABLE);
+
+    server.shutdown().await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn in_flight_requests_finish_after_the_signal() {
+    let issuer = slow_issuer(Duration::from_millis(500)).await;
+    let server = TestApp::with_args(["--auth-jwks-url", &issuer, "--shutdown-delay", "0s"])
+        .serve()
+        .await;
+
+    let request = tokio::spawn(server.get("/v2/me").bearer(&token()).send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let shutdown = tokio::spawn(server.shutdown());
+
+    request
+        .await
+        .unwrap()
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "id": "alice" }));
+    shutdown.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn requests_past_the_deadline_are_abandoned_and_logged() {
+    let (logs, _guard) = capture_logs();
+    let issuer = slow_issuer(Duration::from_secs(60)).await;
+    let server = TestApp::with_args([
+        "--auth-jwks-url",
+        &issuer,
+        "--http-client-timeout",
+        "60s",
+        "--shutdown-delay",
+        "0s",
+        "--shutdown-timeout",
+        "200ms",
+    ])
+    .serve()
+    .await;
+
+    // The client gives up when the server closes the connection, so its result isn't interesting
+    let request = reqwest::Client::new()
+        .get(server.url("/v2/me"))
+        .bearer_auth(token())
+        .header("x-request-id", "a-stuck-request")
+        .send();
+    tokio::spawn(request);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let started = Instant::now();
+    server.shutdown().await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let logs = logs.contents();
+    assert!(logs.contains("abandoning 1 in-flight request(s)"), "{logs}");
+    let abandoned = logs
+        .lines()
+        .find(|line| line.contains("Abandoned request"))
+        .unwrap_or_else(|| panic!("no abandoned request was logged: {logs}"));
+    assert!(
+        abandoned.contains("request_id=\"a-stuck-request\""),
+        "{abandoned}"
+    );
+    assert!(abandoned.contains("uri=/v2/me"), "{abandoned}");
+}
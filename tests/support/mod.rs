@@ -0,0 +1,423 @@
+// Test support for our integration tests
+// `TestApp` builds the exact router we run in production, from a config given as command line flags
+// Requests go straight into the router with `tower::ServiceExt::oneshot`, without any sockets,
+// or over real HTTP once `TestApp::serve` has started the server on an ephemeral port
+// The router is built once per `TestApp`, so anything it keeps between requests carries over, like in production
+// Either way, requests are built with a fluent `TestRequest` and checked with the assertions on `TestResponse`:
+//
+//     let app = TestApp::new();
+//     app.get("/v2/complex").send().await
+//         .assert_status(StatusCode::OK)
+//         .assert_json(json!({ "text": "Hello, World!", "language": "en" }));
+
+// Every test binary includes this module, but none of them uses all of it
+#![allow(dead_code)]
+
+use axum::{
+    body::{Body, Bytes},
+    extract::ConnectInfo,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    Router,
+};
+use rust_starter::{
+    config::Config, listener::BoundListener, logging::LogFilter, shutdown::Shutdown,
+    state::AppState, tls, App,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tower::ServiceExt;
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::{fmt::MakeWriter, reload, EnvFilter, Registry};
+
+// Responses larger than this fail the test, rather than being buffered
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+/// The app under test.
+pub struct TestApp {
+    pub app: App,
+    // Built on the first request and shared by every request after it, like the router of a running server
+    router: OnceLock<Router>,
+    // The filter layer the app's `LogFilter` controls; it isn't installed anywhere, but has to outlive the handle
+    log_layer: reload::Layer<EnvFilter, Registry>,
+}
+
+impl TestApp {
+    /// An app with the default configuration.
+    pub fn new() -> Self {
+        Self::with_args::<[&str; 0]>([])
+    }
+
+    /// An app configured with command line flags, like `["--admin-token", "secret"]`.
+    pub fn with_args<I>(args: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let config = Config::from_args(args).expect("invalid test configuration");
+        tls::install_crypto_provider();
+        let mut app = App::new(&config).expect("failed to create the app");
+        // The admin routes need a log filter to change, which normally comes with the global subscriber
+        let (log_filter, log_layer) = LogFilter::new(&config.log_filter).unwrap();
+        app.log_filter = Some(log_filter);
+        Self {
+            app,
+            router: OnceLock::new(),
+            log_layer,
+        }
+    }
+
+    /// Changes the application state before any request is made, for example to swap in test doubles.
+    pub fn seed(mut self, seed: impl FnOnce(&mut AppState)) -> Self {
+        seed(&mut self.app.state);
+        // The router holds a copy of the state, so it is built again with the new one
+        self.router = OnceLock::new();
+        self
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.app.state
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> TestRequest {
+        let router = self
+            .router
+            .get_or_init(|| self.app.router().expect("failed to build the router"))
+            .clone();
+        TestRequest::new(Transport::Oneshot(router), method, path)
+    }
+
+    pub fn get(&self, path: &str) -> TestRequest {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> TestRequest {
+        self.request(Method::POST, path)
+    }
+
+    pub fn put(&self, path: &str) -> TestRequest {
+        self.request(Method::PUT, path)
+    }
+
+    pub fn delete(&self, path: &str) -> TestRequest {
+        self.request(Method::DELETE, path)
+    }
+
+    /// Serves the app on an ephemeral port on localhost, for tests that need a real connection.
+    pub async fn serve(self) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind an ephemeral port");
+        let addr = listener.local_addr().unwrap();
+        let shutdown = self.app.shutdown.clone();
+        let task = tokio::spawn(self.app.serve(vec![BoundListener::Tcp(listener)]));
+
+        TestServer {
+            addr,
+            client: reqwest::Client::new(),
+            shutdown,
+            task,
+            _log_layer: self.log_layer,
+        }
+    }
+}
+
+/// The app served on an ephemeral port. The server shuts down when this is dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    client: reqwest::Client,
+    shutdown: Shutdown,
+    task: JoinHandle<io::Result<()>>,
+    _log_layer: reload::Layer<EnvFilter, Registry>,
+}
+
+impl TestServer {
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> TestRequest {
+        TestRequest::new(
+            Transport::Http(self.client.clone(), self.url(path)),
+            method,
+            path,
+        )
+    }
+
+    pub fn get(&self, path: &str) -> TestRequest {
+        self.request(Method::GET, path)
+    }
+
+    pub fn post(&self, path: &str) -> TestRequest {
+        self.request(Method::POST, path)
+    }
+
+    /// Triggers a graceful shutdown, without waiting for it.
+    pub fn trigger_shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Triggers a graceful shutdown, and waits for the server to finish.
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.shutdown.trigger();
+        (&mut self.task).await.expect("server task panicked")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+enum Transport {
+    Oneshot(Router),
+    Http(reqwest::Client, String),
+}
+
+/// A request being built.
+#[must_use = "requests are only made when `send` is called"]
+pub struct TestRequest {
+    transport: Transport,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+    peer: SocketAddr,
+}
+
+impl TestRequest {
+    fn new(transport: Transport, method: Method, path: &str) -> Self {
+        Self {
+            transport,
+            method,
+            path: path.to_owned(),
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+            // Like over a real connection to `TestApp::serve`, requests come from localhost
+            peer: SocketAddr::from(([127, 0, 0, 1], 50000)),
+        }
+    }
+
+    pub fn header(mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.headers.append(
+            HeaderName::from_bytes(name.as_ref().as_bytes()).expect("invalid header name"),
+            HeaderValue::from_str(value.as_ref()).expect("invalid header value"),
+        );
+        self
+    }
+
+    pub fn bearer(self, token: &str) -> Self {
+        self.header(header::AUTHORIZATION, format!("Bearer {token}"))
+    }
+
+    /// Sends `body` as JSON.
+    pub fn json(mut self, body: &impl Serialize) -> Self {
+        self.body = serde_json::to_vec(body).unwrap().into();
+        self.header(header::CONTENT_TYPE, "application/json")
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// The address the request comes from, as the peer of its connection.
+    /// Requests over real HTTP always come from localhost, so this only applies to `TestApp` requests.
+    pub fn peer(mut self, peer: &str) -> Self {
+        self.peer = peer.parse().expect("invalid peer address");
+        self
+    }
+
+    pub async fn send(self) -> TestResponse {
+        match self.transport {
+            Transport::Oneshot(router) => {
+                let mut request = Request::builder()
+                    .method(self.method)
+                    .uri(&self.path)
+                    .body(Body::from(self.body))
+                    .expect("invalid request");
+                *request.headers_mut() = self.headers;
+                // What the server adds for every connection
+                request.extensions_mut().insert(ConnectInfo(self.peer));
+
+                let response = router.oneshot(request).await.unwrap();
+                let status = response.status();
+                let headers = response.headers().clone();
+                let body = axum::body::to_bytes(response.into_body(), MAX_BODY)
+                    .await
+                    .expect("failed to read the response body");
+                TestResponse {
+                    status,
+                    headers,
+                    body,
+                }
+            }
+            Transport::Http(client, url) => {
+                let response = client
+                    .request(self.method, url)
+                    .headers(self.headers)
+                    .body(self.body)
+                    .send()
+                    .await
+                    .expect("request failed");
+                TestResponse {
+                    status: response.status(),
+                    headers: response.headers().clone(),
+                    body: response
+                        .bytes()
+                        .await
+                        .expect("failed to read the response body"),
+                }
+            }
+        }
+    }
+}
+
+/// A response, with assertions that panic with the response body, so failures are easy to diagnose.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    #[track_caller]
+    pub fn assert_status(&self, status: StatusCode) -> &Self {
+        assert_eq!(
+            self.status,
+            status,
+            "unexpected status, the body was: {}",
+            self.text()
+        );
+        self
+    }
+
+    #[track_caller]
+    pub fn assert_header(&self, name: impl AsRef<str>, value: &str) -> &Self {
+        assert_eq!(
+            self.header(name.as_ref()),
+            Some(value),
+            "unexpected {} header",
+            name.as_ref()
+        );
+        self
+    }
+
+    /// Asserts that the body is JSON equal to `expected`.
+    #[track_caller]
+    pub fn assert_json(&self, expected: Value) -> &Self {
+        assert_eq!(self.json::<Value>(), expected);
+        self
+    }
+
+    /// Asserts that the body is JSON containing everything in `expected`.
+    /// Objects (including those inside arrays) may have extra fields; everything else must match exactly.
+    #[track_caller]
+    pub fn assert_json_includes(&self, expected: Value) -> &Self {
+        let actual = self.json::<Value>();
+        if let Err(path) = includes(&actual, &expected, String::new()) {
+            panic!(
+                "JSON mismatch at `{path}`\n  expected to include: {expected}\n  actual: {actual}"
+            );
+        }
+        self
+    }
+
+    /// Asserts a problem+json error response with this status.
+    #[track_caller]
+    pub fn assert_problem(&self, status: StatusCode) -> &Self {
+        self.assert_status(status)
+            .assert_header(header::CONTENT_TYPE, "application/problem+json")
+            .assert_json_includes(serde_json::json!({
+                "type": "about:blank",
+                "title": status.canonical_reason().unwrap(),
+                "status": status.as_u16(),
+            }))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(name)
+            .map(|value| value.to_str().expect("header isn't valid text"))
+    }
+
+    #[track_caller]
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|error| panic!("invalid JSON body ({error}): {}", self.text()))
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+// Whether `actual` includes `expected`, or the path of the first difference
+fn includes(actual: &Value, expected: &Value, path: String) -> Result<(), String> {
+    match (actual, expected) {
+        (Value::Object(actual), Value::Object(expected)) => {
+            for (key, expected) in expected {
+                let path = format!("{path}.{key}");
+                let actual = actual.get(key).ok_or_else(|| path.clone())?;
+                includes(actual, expected, path)?;
+            }
+            Ok(())
+        }
+        (Value::Array(actual), Value::Array(expected)) if actual.len() == expected.len() => {
+            for (index, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+                includes(actual, expected, format!("{path}[{index}]"))?;
+            }
+            Ok(())
+        }
+        _ if actual == expected => Ok(()),
+        _ => Err(if path.is_empty() { ".".into() } else { path }),
+    }
+}
+
+/// Everything logged while the guard from `capture_logs` is alive.
+#[derive(Clone, Default)]
+pub struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Logs {
+    type Writer = Logs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Captures the `info` and more severe events logged on this thread, until the returned guard is dropped.
+/// `#[tokio::test]` runs every task on the test's own thread, so that includes the app's events.
+pub fn capture_logs() -> (Logs, DefaultGuard) {
+    let logs = Logs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .with_env_filter(EnvFilter::new("info"))
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
@@ -0,0 +1,134 @@
+// Trace export over OTLP/HTTP, and trace context on our outgoing requests
+// The subscriber is global, so everything is checked in a single test
+
+mod support;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use jsonwebtoken::{jwk::Jwk, jwk::JwkSet, Algorithm, EncodingKey, Header};
+use rust_starter::{config::Config, logging, telemetry::Telemetry, tls};
+use serde_json::json;
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use support::TestApp;
+use tokio::net::TcpListener;
+
+const RSA_KEY: &str = include_str!("fixtures/jwt-rsa.pem");
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_ID: &str = "00f067aa0ba902b7";
+
+// Serves `router` on an ephemeral port, returning its base URL
+async fn start(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    url
+}
+
+fn bytes(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn traces_are_exported_and_passed_on() {
+    // A collector, which keeps every batch of spans it receives
+    let batches = Arc::new(Mutex::new(Vec::<Bytes>::new()));
+    let collector = start(
+        Router::new()
+            .route(
+                "/v1/traces",
+                post(
+                    |State(batches): State<Arc<Mutex<Vec<Bytes>>>>, body: Bytes| async move {
+                        batches.lock().unwrap().push(body);
+                        StatusCode::OK
+                    },
+                ),
+            )
+            .with_state(batches.clone()),
+    )
+    .await;
+
+    // An issuer, which remembers the `traceparent` of every JWKS fetch
+    let key = EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap();
+    let mut jwk = Jwk::from_encoding_key(&key, Algorithm::RS256).unwrap();
+    jwk.common.key_id = Some("rsa".into());
+    let traceparents = Arc::new(Mutex::new(Vec::<String>::new()));
+    let issuer = start(
+        Router::new()
+            .route(
+                "/jwks.json",
+                get(
+                    |State((jwks, traceparents)): State<(JwkSet, Arc<Mutex<Vec<String>>>)>,
+                     headers: HeaderMap| async move {
+                        if let Some(traceparent) = headers.get("traceparent") {
+                            let traceparent = traceparent.to_str().unwrap().to_owned();
+                            traceparents.lock().unwrap().push(traceparent);
+                        }
+                        Json(jwks)
+                    },
+                ),
+            )
+            .with_state((JwkSet { keys: vec![jwk] }, traceparents.clone())),
+    )
+    .await;
+
+    let args = [
+        "--otel-endpoint".to_owned(),
+        collector,
+        "--otel-protocol".to_owned(),
+        "http".to_owned(),
+        "--auth-jwks-url".to_owned(),
+        format!("{issuer}/jwks.json"),
+        "--log-filter".to_owned(),
+        "info".to_owned(),
+    ];
+    let config = Config::from_args(args.clone()).unwrap();
+    // The exporter's HTTP client uses rustls too
+    tls::install_crypto_provider();
+    let telemetry = Telemetry::init(&config).unwrap().unwrap();
+    logging::init(&config, Some(&telemetry)).unwrap();
+    let app = TestApp::with_args(args);
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("rsa".into());
+    let exp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+    let token =
+        jsonwebtoken::encode(&header, &json!({ "sub": "alice", "exp": exp }), &key).unwrap();
+    app.get("/v2/me")
+        .bearer(&token)
+        .header("traceparent", format!("00-{TRACE_ID}-{PARENT_ID}-01"))
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+
+    // The JWKS was fetched as part of the caller's trace, from a span of our own
+    let traceparents = traceparents.lock().unwrap().clone();
+    assert_eq!(traceparents.len(), 1);
+    let parts: Vec<_> = traceparents[0].split('-').collect();
+    assert_eq!(parts[1], TRACE_ID);
+    assert_ne!(parts[2], PARENT_ID);
+
+    // Shutting down flushes our spans to the collector
+    telemetry.shutdown().await;
+    let trace_id = bytes(TRACE_ID);
+    let exported = batches.lock().unwrap().iter().any(|batch| {
+        batch
+            .windows(trace_id.len())
+            .any(|window| window == trace_id.as_slice())
+    });
+    assert!(exported, "no spans of the trace reached the collector");
+}
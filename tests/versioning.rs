@@ -0,0 +1,132 @@
+// API versions, and the lifecycle headers on deprecated ones
+
+mod support;
+
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+use support::TestApp;
+
+#[tokio::test]
+async fn each_version_has_its_own_contract() {
+    let app = TestApp::new();
+
+    app.get("/v1/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "message": "Hello, World!" }));
+    app.get("/v2/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_json(json!({ "text": "Hello, World!", "language": "en" }));
+}
+
+#[tokio::test]
+async fn unchanged_handlers_are_shared_between_versions() {
+    let app = TestApp::new();
+
+    for path in ["/v1", "/v2"] {
+        let response = app.get(path).send().await;
+        response.assert_status(StatusCode::OK);
+        assert_eq!(response.text(), "Hello, World!");
+    }
+}
+
+#[tokio::test]
+async fn deprecated_versions_point_to_their_successor() {
+    let response = TestApp::new().get("/v1/complex").send().await;
+
+    response
+        .assert_status(StatusCode::OK)
+        .assert_header("deprecation", "@1792022400")
+        .assert_header("link", r#"</v2>; rel="successor-version""#);
+    assert_eq!(response.header("sunset"), None);
+}
+
+#[tokio::test]
+async fn unversioned_aliases_are_deprecated_like_v1() {
+    TestApp::new()
+        .get("/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("deprecation", "@1792022400")
+        .assert_header("link", r#"</v2>; rel="successor-version""#)
+        .assert_json(json!({ "message": "Hello, World!" }));
+}
+
+#[tokio::test]
+async fn the_deprecation_date_is_configurable() {
+    let app = TestApp::with_args(["--api-v1-deprecated", "2027-01-01"]);
+
+    for path in ["/v1/complex", "/complex"] {
+        app.get(path)
+            .send()
+            .await
+            .assert_header("deprecation", "@1798761600");
+    }
+}
+
+#[tokio::test]
+async fn current_versions_have_no_lifecycle_headers() {
+    let response = TestApp::new().get("/v2/complex").send().await;
+
+    assert_eq!(response.header("deprecation"), None);
+    assert_eq!(response.header("link"), None);
+    assert_eq!(response.header("sunset"), None);
+}
+
+#[tokio::test]
+async fn a_sunset_date_is_announced() {
+    TestApp::with_args(["--api-v1-sunset", "2999-01-01"])
+        .get("/v1/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK)
+        .assert_header("sunset", "Tue, 01 Jan 2999 00:00:00 GMT");
+}
+
+#[tokio::test]
+async fn versions_are_gone_after_their_sunset() {
+    let app = TestApp::with_args(["--api-v1-sunset", "2020-01-01T00:00:00Z"]);
+
+    app.get("/v1/complex")
+        .send()
+        .await
+        .assert_problem(StatusCode::GONE)
+        .assert_header("sunset", "Wed, 01 Jan 2020 00:00:00 GMT")
+        .assert_json_includes(json!({ "successor": "/v2" }));
+    app.get("/v2/complex")
+        .send()
+        .await
+        .assert_status(StatusCode::OK);
+}
+
+#[tokio::test]
+async fn invalid_sunset_dates_are_rejected() {
+    let error = rust_starter::config::Config::from_args(["--api-v1-sunset", "soon"]).unwrap_err();
+
+    assert!(error.to_string().contains("api.v1_sunset"), "{error}");
+}
+
+#[tokio::test]
+async fn the_document_marks_deprecated_operations() {
+    let document: Value = TestApp::new().get("/openapi.json").send().await.json();
+
+    assert_eq!(document["paths"]["/v1/complex"]["get"]["deprecated"], true);
+    assert!(document["paths"]["/v2/complex"]["get"]["deprecated"].is_null());
+    assert_eq!(
+        document["paths"]["/v1/complex"]["get"]["operationId"],
+        "v1_complex"
+    );
+    assert_eq!(
+        document["paths"]["/v2/complex"]["get"]["operationId"],
+        "v2_complex"
+    );
+    assert_eq!(document["paths"]["/complex"]["get"]["deprecated"], true);
+    assert_eq!(
+        document["paths"]["/complex"]["get"]["operationId"],
+        "complex"
+    );
+}